@@ -1,3 +1,5 @@
+use std::{collections::HashMap, fmt};
+
 use chrono::{DateTime, NaiveDate, Utc};
 use rust_decimal::Decimal;
 use serde::{de::IgnoredAny, Deserialize, Serialize};
@@ -23,14 +25,132 @@ pub(crate) struct Account {
     pub(crate) created: DateTime<Utc>,
     #[serde(skip_serializing, rename = "last_accessed")]
     pub(crate) _last_accessed: IgnoredAny,
-    pub(crate) iban: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) iban: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) bban: Option<String>,
+    #[serde(
+        rename = "maskedPan",
+        alias = "masked_pan",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) masked_pan: Option<String>,
+    #[serde(
+        rename = "resourceId",
+        alias = "resource_id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) resource_id: Option<String>,
     pub(crate) status: AccountStatus,
     pub(crate) institution_id: String,
-    pub(crate) owner_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) owner_name: Option<String>,
+    #[serde(flatten)]
+    pub(crate) other: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AccountDetailsResponse {
+    pub(crate) account: AccountDetails,
     #[serde(flatten)]
     pub(crate) other: serde_json::Value,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AccountDetails {
+    #[serde(
+        rename = "resourceId",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) resource_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) iban: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) bban: Option<String>,
+    #[serde(rename = "maskedPan", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) masked_pan: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) currency: Option<String>,
+    #[serde(rename = "ownerName", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) owner_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) product: Option<String>,
+    #[serde(
+        rename = "cashAccountType",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) cash_account_type: Option<String>,
+    #[serde(flatten)]
+    pub(crate) other: serde_json::Value,
+}
+
+/// What we keep on disk from `/accounts/{id}/details/`, so we know when the
+/// details were last fetched. Stored as `account-details-cache.json`; not to
+/// be confused with `account-details.json`, which holds the `/accounts/{id}/`
+/// metadata and is rewritten on every sync.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CachedAccountDetails {
+    pub(crate) fetched_at: DateTime<Utc>,
+    pub(crate) details: AccountDetails,
+}
+
+impl Account {
+    /// The name of the directory we store this account's data under. An
+    /// alias configured against any of the account's identifiers wins,
+    /// otherwise we use the most stable identifier the bank gave us.
+    pub(crate) fn directory_key(&self, aliases: &HashMap<String, String>) -> String {
+        let id = self.id.to_string();
+        let identifiers = [
+            self.iban.as_deref(),
+            self.bban.as_deref(),
+            self.masked_pan.as_deref(),
+            self.resource_id.as_deref(),
+            Some(id.as_str()),
+        ];
+        let mut identifiers = identifiers
+            .into_iter()
+            .flatten()
+            .filter(|ident| !ident.trim().is_empty());
+
+        let key = identifiers
+            .clone()
+            .find_map(|ident| aliases.get(ident))
+            .map(String::as_str)
+            .or_else(|| identifiers.next())
+            .unwrap_or(id.as_str());
+
+        sanitise_path_component(key)
+    }
+}
+
+// Keeps identifiers such as masked card numbers from escaping the output
+// directory, or being awkward to handle in a shell.
+fn sanitise_path_component(key: &str) -> String {
+    let key = key
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    if key.chars().all(|c| c == '.') {
+        key.replace('.', "_")
+    } else {
+        key
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Balances {
     pub(crate) balances: Vec<Balance>,
@@ -62,3 +182,15 @@ pub(crate) struct BalanceAmount {
     #[serde(flatten)]
     pub(crate) other: serde_json::Value,
 }
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountStatus::Ready => f.write_str("READY"),
+            AccountStatus::Expired => f.write_str("EXPIRED"),
+            AccountStatus::Error => f.write_str("ERROR"),
+            AccountStatus::Suspended => f.write_str("SUSPENDED"),
+            AccountStatus::Other(status) => f.write_str(status),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use chrono::{DateTime, Days, Utc};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use tracing::{debug, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    client::BankDataClient,
+    config::{ProviderConfig, ProviderState},
+    institutions::Institution,
+};
+
+const DEFAULT_MAX_HISTORICAL_DAYS: u64 = 90;
+const DEFAULT_ACCESS_VALID_FOR_DAYS: u64 = 90;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AccessScope {
+    Balances,
+    Details,
+    Transactions,
+}
+
+#[derive(Debug, Serialize)]
+struct EndUserAgreementReq {
+    institution_id: String,
+    max_historical_days: u64,
+    access_valid_for_days: u64,
+    access_scope: Vec<AccessScope>,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct EndUserAgreement {
+    pub(crate) id: Uuid,
+    pub(crate) created: DateTime<Utc>,
+    pub(crate) institution_id: String,
+    #[serde_as(as = "serde_with::PickFirst<(_, serde_with::DisplayFromStr)>")]
+    pub(crate) max_historical_days: u64,
+    #[serde_as(as = "serde_with::PickFirst<(_, serde_with::DisplayFromStr)>")]
+    pub(crate) access_valid_for_days: u64,
+    pub(crate) access_scope: Vec<AccessScope>,
+    #[serde(default)]
+    pub(crate) accepted: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub(crate) other: serde_json::Value,
+}
+
+#[instrument(skip_all, fields(institution_id = %institution.id))]
+pub(crate) async fn create_agreement(
+    client: &BankDataClient,
+    provider_config: &ProviderConfig,
+    institution: &Institution,
+) -> Result<EndUserAgreement> {
+    let max_historical_days = clamp_days(
+        "max_historical_days",
+        provider_config
+            .max_historical_days
+            .unwrap_or(DEFAULT_MAX_HISTORICAL_DAYS),
+        institution.transaction_total_days,
+    );
+    let access_valid_for_days = clamp_days(
+        "access_valid_for_days",
+        provider_config
+            .access_valid_for_days
+            .unwrap_or(DEFAULT_ACCESS_VALID_FOR_DAYS),
+        institution.max_access_valid_for_days,
+    );
+    let access_scope = provider_config.access_scope.clone().unwrap_or_else(|| {
+        vec![
+            AccessScope::Balances,
+            AccessScope::Details,
+            AccessScope::Transactions,
+        ]
+    });
+
+    let req = EndUserAgreementReq {
+        institution_id: institution.id.clone(),
+        max_historical_days,
+        access_valid_for_days,
+        access_scope,
+    };
+
+    debug!(?req);
+
+    let agreement = client
+        .post::<EndUserAgreement>("/api/v2/agreements/enduser/", &req)
+        .await?;
+
+    debug!(?agreement, "Created agreement");
+
+    Ok(agreement)
+}
+
+#[instrument(skip(client))]
+pub(crate) async fn fetch_agreement(client: &BankDataClient, id: Uuid) -> Result<EndUserAgreement> {
+    let agreement = client
+        .get::<EndUserAgreement>(&format!("/api/v2/agreements/enduser/{}/", id))
+        .await?;
+    Ok(agreement)
+}
+
+/// Fills in the consent dates on the provider state from its agreement,
+/// if we know which agreement that is and it has been accepted.
+pub(crate) async fn record_consent(
+    client: &BankDataClient,
+    state: &mut ProviderState,
+) -> Result<()> {
+    let Some(agreement_id) = state.agreement_id else {
+        debug!("No agreement recorded; consent dates unknown");
+        return Ok(());
+    };
+
+    let agreement = fetch_agreement(client, agreement_id).await?;
+    apply_consent(&agreement, state);
+
+    Ok(())
+}
+
+pub(crate) fn apply_consent(agreement: &EndUserAgreement, state: &mut ProviderState) {
+    state.agreement_accepted = agreement.accepted;
+    state.consent_expires = agreement.expires_at();
+}
+
+impl EndUserAgreement {
+    pub(crate) fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.accepted?
+            .checked_add_days(Days::new(self.access_valid_for_days))
+    }
+}
+
+fn clamp_days(name: &str, requested: u64, institution_max: u64) -> u64 {
+    if requested > institution_max {
+        warn!(
+            %name, %requested, %institution_max,
+            "Requested period exceeds what the institution supports; clamping"
+        );
+        institution_max
+    } else {
+        requested
+    }
+}
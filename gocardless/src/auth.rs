@@ -5,28 +5,33 @@ use std::{
 
 use chrono::{DateTime, Duration, Utc};
 use clap::Args;
-use color_eyre::Result;
+use color_eyre::{eyre::eyre, Result};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, instrument, warn};
 
-use crate::{client::BankDataClient, files::write_json_atomically};
+use crate::{
+    client::BankDataClient,
+    config::{ApiConfig, ScraperConfig},
+    files::write_json_atomically,
+    lock::FileLock,
+    ratelimit::RateLimiter,
+    secrets::SecretSource,
+};
 
 const EXPIRY_GRACE_PERIOD: Duration = Duration::minutes(1);
 
 #[derive(Debug, Clone, Args)]
 pub struct AuthArgs {
-    #[clap(short = 's', long = "secrets", help = "Secrets file")]
-    secrets: PathBuf,
+    #[clap(
+        short = 's',
+        long = "secrets",
+        help = "Secrets file; overrides any secret source in the config"
+    )]
+    secrets: Option<PathBuf>,
     #[clap(short = 't', long = "token", help = "Token file")]
     token: PathBuf,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) struct Secrets {
-    secret_id: String,
-    secret_key: String,
-}
-
 #[derive(Debug, Clone, Serialize)]
 pub(crate) struct TokenRefreshReq {
     refresh: String,
@@ -82,7 +87,11 @@ async fn store_token(path: &Path, tok: &Token) -> Result<()> {
 }
 
 #[instrument(skip_all, fields(?path))]
-async fn load_token(path: &Path) -> Result<Option<Token>> {
+async fn load_token(
+    path: &Path,
+    api: &ApiConfig,
+    rate_limiter: &RateLimiter,
+) -> Result<Option<Token>> {
     let buf = match tokio::fs::read(path).await {
         Ok(buf) => buf,
         Err(err) if err.kind() == io::ErrorKind::NotFound => {
@@ -104,7 +113,7 @@ async fn load_token(path: &Path) -> Result<Option<Token>> {
 
     if token.access_expires - EXPIRY_GRACE_PERIOD <= now {
         debug!(expired_at=?token.access_expires, "Access token expired, refreshing");
-        token = refresh_token(&token).await?;
+        token = refresh_token(&token, api, rate_limiter).await?;
 
         store_token(path, &token).await?;
     }
@@ -115,8 +124,12 @@ async fn load_token(path: &Path) -> Result<Option<Token>> {
 }
 
 #[instrument(skip_all)]
-async fn refresh_token(token: &Token) -> Result<Token> {
-    let client = BankDataClient::unauthenticated();
+async fn refresh_token(
+    token: &Token,
+    api: &ApiConfig,
+    rate_limiter: &RateLimiter,
+) -> Result<Token> {
+    let client = BankDataClient::unauthenticated(api, rate_limiter.clone())?;
 
     let authed_at = Utc::now();
 
@@ -134,21 +147,29 @@ async fn refresh_token(token: &Token) -> Result<Token> {
     Ok(tok)
 }
 
-#[instrument(skip_all, fields(?path))]
-async fn load_secrets(path: &Path) -> Result<Secrets> {
-    let buf = tokio::fs::read(&path).await?;
-    let secrets = serde_json::from_slice(&buf)?;
+impl AuthArgs {
+    /// Loads (or fetches) a token and builds a client around it, with a
+    /// single view of the rate limits for both.
+    pub(crate) async fn client(&self, config: &ScraperConfig) -> Result<BankDataClient> {
+        let rate_limiter = RateLimiter::new(&config.rate_limits);
+        let token = self.load_token(config, &rate_limiter).await?;
+        BankDataClient::new(token, config, rate_limiter)
+    }
 
-    debug!(?path, "Loaded secrets");
+    async fn load_token(
+        &self,
+        config: &ScraperConfig,
+        rate_limiter: &RateLimiter,
+    ) -> Result<Token> {
+        let api = &config.api;
 
-    Ok(secrets)
-}
+        // Refreshing invalidates the old access token, so only one process
+        // may do so at a time; the others pick up the result.
+        let _lock = FileLock::acquire(&self.token.with_extension("lock")).await?;
 
-impl AuthArgs {
-    pub(crate) async fn load_token(&self) -> Result<Token> {
         let authed_at = Utc::now();
 
-        if let Some(token) = load_token(&self.token).await? {
+        if let Some(token) = load_token(&self.token, api, rate_limiter).await? {
             if token.refresh_expires >= authed_at {
                 return Ok(token);
             } else {
@@ -156,11 +177,11 @@ impl AuthArgs {
             }
         }
 
-        let secrets = load_secrets(&self.secrets).await?;
+        let secrets = self.secret_source(config)?.load().await?;
 
         info!("Authing");
 
-        let client = BankDataClient::unauthenticated();
+        let client = BankDataClient::unauthenticated(api, rate_limiter.clone())?;
 
         let tokens = client
             .post::<TokenPair>("/api/v2/token/new/", &secrets)
@@ -172,4 +193,15 @@ impl AuthArgs {
 
         Ok(tok)
     }
+
+    fn secret_source(&self, config: &ScraperConfig) -> Result<SecretSource> {
+        if let Some(path) = &self.secrets {
+            return Ok(SecretSource::File { path: path.clone() });
+        }
+
+        config
+            .secrets
+            .clone()
+            .ok_or_else(|| eyre!("No secrets: pass --secrets or configure [secrets]"))
+    }
 }
@@ -1,51 +1,50 @@
-use std::fmt;
-
 use again::RetryPolicy;
-use axum::http::{uri::Scheme, Uri};
-use chrono::{DateTime, Duration, Utc};
-use color_eyre::{
-    eyre::{eyre, Context},
-    Result,
+use axum::http::Uri;
+use chrono::{Duration, Utc};
+use color_eyre::{eyre::Context, Result};
+use reqwest::{header::CONTENT_TYPE, Client, RequestBuilder};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{debug, info, trace, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::Token,
+    config::{ApiConfig, ScraperConfig},
+    error::{ClientError, ErrorResponse},
+    ratelimit::{EndpointClass, Quota, QuotaScope, RateLimiter},
 };
-use reqwest::{header::CONTENT_TYPE, Client};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tracing::{debug, trace, warn};
-
-use crate::{auth::Token, config::RetryConfig};
 
-const BANK_DATA_HOST: &str = "bankaccountdata.gocardless.com";
+// How many consecutive 429s with a short `Retry-After` we will wait out and
+// resend for one call. Each is waited out once, by `send` alone.
+const MAX_RATE_LIMITED_RETRIES: usize = 3;
 
 #[derive(Clone)]
 pub(crate) struct UnauthenticatedBankDataClient {
     http: Client,
+    base_url: Uri,
+    rate_limiter: RateLimiter,
 }
 #[derive(Clone)]
 pub(crate) struct BankDataClient {
     http: Client,
+    base_url: Uri,
     token: Token,
     retry_policy: RetryPolicy,
+    rate_limiter: RateLimiter,
 }
-#[derive(Debug, Deserialize)]
-struct ErrorResponse {
-    #[serde(default)]
-    summary: String,
-    #[serde(default)]
-    detail: String,
-    #[serde(default)]
-    status_code: u16,
-    #[serde(flatten)]
-    other: serde_json::Value,
-}
-
 pub(crate) trait ResponseExt: Sized {
-    async fn parse_error(self) -> Result<Self>;
-    fn log_rate_limits(self, started_at: DateTime<Utc>) -> Result<Self>;
+    async fn parse_error(self) -> Result<Self, ClientError>;
 }
 
 impl UnauthenticatedBankDataClient {
-    fn new() -> Self {
+    fn new(api: &ApiConfig, rate_limiter: RateLimiter) -> Result<Self> {
         let http = Client::new();
-        UnauthenticatedBankDataClient { http }
+        let base_url = api.base_url()?;
+        Ok(UnauthenticatedBankDataClient {
+            http,
+            base_url,
+            rate_limiter,
+        })
     }
 
     pub(crate) async fn post<Response: DeserializeOwned>(
@@ -54,82 +53,90 @@ impl UnauthenticatedBankDataClient {
         body: &impl Serialize,
     ) -> Result<Response> {
         // "https://bankaccountdata.gocardless.com/api/v2/token/new/"
-        let url = Uri::builder()
-            .scheme(Scheme::HTTPS)
-            .authority(BANK_DATA_HOST)
-            .path_and_query(path)
-            .build()
-            .wrap_err("Build base URI")?
-            .to_string();
+        let url = build_url(&self.base_url, path)?;
 
         debug!(%url, "POST");
 
-        let started_at = Utc::now();
+        let resp = send(&self.rate_limiter, path, || self.http.post(&url).json(body)).await?;
 
-        let resp = self
-            .http
-            .post(url)
-            .json(body)
-            .send()
-            .await?
-            .log_rate_limits(started_at)?
-            .parse_error()
-            .await?;
-
-        log_rate_limits(&resp, started_at)?;
-
-        let data = resp.json().await?;
+        let data = resp.json().await.map_err(ClientError::from)?;
 
         Ok(data)
     }
 }
 
 impl BankDataClient {
-    pub(crate) fn new(token: Token, retries: &RetryConfig) -> Self {
+    /// Shares `rate_limiter` with the client that fetched the token, so both
+    /// see the same quotas.
+    pub(crate) fn new(
+        token: Token,
+        config: &ScraperConfig,
+        rate_limiter: RateLimiter,
+    ) -> Result<Self> {
         let http = Client::new();
+        let base_url = config.api.base_url()?;
 
-        let retry_policy = retries.as_retry_policy();
+        let retry_policy = config.retries.as_retry_policy();
 
-        Self {
+        Ok(Self {
             http,
+            base_url,
             token,
             retry_policy,
-        }
+            rate_limiter,
+        })
+    }
+
+    pub(crate) fn unauthenticated(
+        api: &ApiConfig,
+        rate_limiter: RateLimiter,
+    ) -> Result<UnauthenticatedBankDataClient> {
+        UnauthenticatedBankDataClient::new(api, rate_limiter)
     }
 
-    pub(crate) fn unauthenticated() -> UnauthenticatedBankDataClient {
-        UnauthenticatedBankDataClient::new()
+    pub(crate) fn account_quota(&self, account_id: Uuid, class: EndpointClass) -> Option<Quota> {
+        self.rate_limiter.account_quota(account_id, class)
     }
 
     pub(crate) async fn get<Response: DeserializeOwned>(&self, path: &str) -> Result<Response> {
         // "https://bankaccountdata.gocardless.com/api/v2/token/new/"
-        let url = Uri::builder()
-            .scheme(Scheme::HTTPS)
-            .authority(BANK_DATA_HOST)
-            .path_and_query(path)
-            .build()
-            .wrap_err("Build base URI")?
-            .to_string();
-
-        let started_at = Utc::now();
+        let url = build_url(&self.base_url, path)?;
 
         debug!(%url, "GET");
         let resp = self
             .retry_policy
-            .retry(|| async {
-                self.http
-                    .get(&url)
-                    .bearer_auth(&self.token.access)
-                    .send()
-                    .await?
-                    .parse_error()
-                    .await
-            })
+            .retry_if(
+                || {
+                    send(&self.rate_limiter, path, || {
+                        self.http.get(&url).bearer_auth(&self.token.access)
+                    })
+                },
+                is_retryable,
+            )
             .await?;
 
-        log_rate_limits(&resp, started_at)?;
+        let data = resp.json().await.map_err(ClientError::from)?;
 
-        let data = resp.json().await?;
+        Ok(data)
+    }
+
+    pub(crate) async fn delete<Response: DeserializeOwned>(&self, path: &str) -> Result<Response> {
+        let url = build_url(&self.base_url, path)?;
+
+        debug!(%url, "DELETE");
+        let resp = self
+            .retry_policy
+            .retry_if(
+                || {
+                    send(&self.rate_limiter, path, || {
+                        self.http.delete(&url).bearer_auth(&self.token.access)
+                    })
+                },
+                is_retryable,
+            )
+            .await?;
+
+        let data = resp.json().await.map_err(ClientError::from)?;
 
         Ok(data)
     }
@@ -140,147 +147,206 @@ impl BankDataClient {
         body: &impl Serialize,
     ) -> Result<Response> {
         // "https://bankaccountdata.gocardless.com/api/v2/token/new/"
-        let url = Uri::builder()
-            .scheme(Scheme::HTTPS)
-            .authority(BANK_DATA_HOST)
-            .path_and_query(path)
-            .build()
-            .wrap_err("Build base URI")?
-            .to_string();
-
-        let started_at = Utc::now();
+        let url = build_url(&self.base_url, path)?;
 
         debug!(%url, "POST");
         let resp = self
             .retry_policy
-            .retry(|| async {
-                self.http
-                    .post(&url)
-                    .json(body)
-                    .bearer_auth(&self.token.access)
-                    .send()
-                    .await?
-                    .log_rate_limits(started_at)?
-                    .parse_error()
-                    .await
-            })
+            .retry_if(
+                || {
+                    send(&self.rate_limiter, path, || {
+                        self.http
+                            .post(&url)
+                            .json(body)
+                            .bearer_auth(&self.token.access)
+                    })
+                },
+                // POSTs create agreements and requisitions, so retrying once
+                // the server may have acted risks creating duplicates.
+                is_retryable_unsent,
+            )
             .await?;
 
-        let data = resp.json().await?;
+        let data = resp.json().await.map_err(ClientError::from)?;
 
         Ok(data)
     }
 }
 
-const HTTP_X_RATELIMIT_LIMIT: &str = "HTTP_X_RATELIMIT_LIMIT";
-const HTTP_X_RATELIMIT_REMAINING: &str = "HTTP_X_RATELIMIT_REMAINING";
-const HTTP_X_RATELIMIT_RESET: &str = "HTTP_X_RATELIMIT_RESET";
-const HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_LIMIT: &str = "HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_LIMIT";
-const HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING: &str =
-    "HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING";
-const HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET: &str = "HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET";
+async fn send(
+    rate_limiter: &RateLimiter,
+    path: &str,
+    build: impl Fn() -> RequestBuilder,
+) -> Result<reqwest::Response, ClientError> {
+    let mut attempts = 0;
+    loop {
+        rate_limiter.acquire(path).await?;
 
-fn log_rate_limits(resp: &reqwest::Response, started_at: DateTime<Utc>) -> Result<()> {
-    let Some(limit) = maybe_parse_header(resp, HTTP_X_RATELIMIT_LIMIT)? else {
-        warn!(header=%HTTP_X_RATELIMIT_LIMIT, "rate limit header missing");
-        return Ok(());
-    };
-
-    let Some(remaining) = maybe_parse_header(resp, HTTP_X_RATELIMIT_REMAINING)? else {
-        warn!(header=%HTTP_X_RATELIMIT_REMAINING, "rate limit header missing");
-        return Ok(());
-    };
-
-    let Some(reset) = maybe_parse_header(resp, HTTP_X_RATELIMIT_RESET)? else {
-        warn!(header=%HTTP_X_RATELIMIT_RESET, "rate limit header missing");
-        return Ok(());
-    };
-
-    let reset_at = started_at + Duration::seconds(reset);
-
-    debug!(%limit, %remaining, %reset_at, "Rate limit status");
-
-    let Some(limit) = maybe_parse_header(resp, HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_LIMIT)? else {
-        return Ok(());
-    };
+        let started_at = Utc::now();
+        let resp = build().send().await?;
 
-    let Some(remaining) = maybe_parse_header(resp, HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING)?
-    else {
-        return Ok(());
-    };
+        rate_limiter.record(path, resp.headers(), started_at)?;
 
-    let Some(reset) = maybe_parse_header(resp, HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET)? else {
-        return Ok(());
-    };
+        let err = match resp.parse_error().await {
+            Ok(resp) => return Ok(resp),
+            Err(ClientError::RateLimited { retry_after, .. }) => {
+                rate_limited(rate_limiter, path, retry_after)
+            }
+            Err(err) => return Err(err),
+        };
+
+        // The server has not acted on the request, so it is safe to resend
+        // whatever the method; the caller's retry policy is not involved.
+        match err {
+            ClientError::RateLimited {
+                retry_after: Some(delay),
+                ..
+            } if attempts < MAX_RATE_LIMITED_RETRIES => {
+                attempts += 1;
+                info!(?delay, attempts, "Rate limited; waiting before retrying");
+                tokio::time::sleep(delay).await;
+            }
+            err => return Err(err),
+        }
+    }
+}
 
-    let reset_at = started_at + Duration::seconds(reset);
+// Works out how long we should wait after being told we are rate limited,
+// preferring the server's `Retry-After`, so long as that is short enough.
+fn rate_limited(
+    rate_limiter: &RateLimiter,
+    path: &str,
+    retry_after: Option<std::time::Duration>,
+) -> ClientError {
+    let now = Utc::now();
+    let exhausted = rate_limiter.exhausted(path);
+    let scope = exhausted.map(|(scope, _)| scope);
+
+    let retry_after = retry_after
+        .or_else(|| match exhausted {
+            Some((QuotaScope::Endpoint(_), until)) => (until - now).to_std().ok(),
+            _ => None,
+        })
+        .filter(|delay| {
+            Duration::from_std(*delay)
+                .map(|delay| delay <= rate_limiter.max_wait())
+                .unwrap_or(false)
+        });
+    let until = exhausted.map(|(_, until)| until).or_else(|| {
+        retry_after
+            .and_then(|delay| Duration::from_std(delay).ok())
+            .map(|delay| now + delay)
+    });
+
+    ClientError::RateLimited {
+        scope,
+        until,
+        retry_after,
+    }
+}
 
-    debug!(%limit, %remaining, %reset_at, "Account rate limit status");
+fn is_retryable(err: &ClientError) -> bool {
+    let transient = err.is_transient();
+    if transient {
+        warn!(error=%err, "Transient error; retrying");
+    }
+    transient
+}
 
-    Ok(())
+fn is_retryable_unsent(err: &ClientError) -> bool {
+    let unsent = err.is_unsent();
+    if unsent {
+        warn!(error=%err, "Request not sent; retrying");
+    }
+    unsent
 }
 
-fn maybe_parse_header(resp: &reqwest::Response, header: &str) -> Result<Option<i64>> {
-    let Some(limit) = resp.headers().get(header) else {
-        trace!(header=%header, "rate limit header missing");
-        return Ok(None);
-    };
-    let s = limit.to_str().wrap_err_with(|| header.to_owned())?;
-    let limit = s.parse().wrap_err_with(|| header.to_owned())?;
-    Ok(Some(limit))
+fn build_url(base_url: &Uri, path_and_query: &str) -> Result<String> {
+    let prefix = base_url.path().trim_end_matches('/');
+    let url = http::uri::Builder::from(base_url.clone())
+        .path_and_query(format!("{prefix}{path_and_query}"))
+        .build()
+        .wrap_err("Build request URI")?
+        .to_string();
+    Ok(url)
 }
 
 impl ResponseExt for reqwest::Response {
-    async fn parse_error(self) -> Result<Self> {
+    async fn parse_error(self) -> Result<Self, ClientError> {
         let resp = self;
-        if resp.status().is_client_error() {
-            match resp.headers().get(CONTENT_TYPE) {
-                Some(content_type) if content_type.as_bytes() == b"application/json" => {
-                    let err = resp.json::<ErrorResponse>().await?;
-                    return Err(err.into());
-                }
-                Some(content_type) => {
-                    warn!(?content_type, "unknown content type");
-                    let status = resp.status();
-                    let content = resp.text().await?;
-                    debug!(?content, "Response body");
-                    return Err(eyre!(
-                        "Unrecognised response; code: {status}; content: {content:?}"
-                    ));
-                }
-                None => {}
-            }
+        let status = resp.status();
+        trace!(?status, headers=?resp.headers());
+        if status.is_success() {
+            return Ok(resp);
         }
 
-        trace!(status=?resp.status(), headers=?resp.headers());
-        Ok(resp.error_for_status()?)
+        let headers = resp.headers().clone();
+        let is_json = match headers.get(CONTENT_TYPE) {
+            Some(content_type) if content_type.as_bytes() == b"application/json" => true,
+            Some(content_type) => {
+                warn!(?content_type, "unknown content type");
+                false
+            }
+            None => false,
+        };
+        let body = resp.text().await?;
+        debug!(?body, "Response body");
+
+        // Proxies in front of the API send their own errors, which need not
+        // look like ours; the status alone still tells us what went wrong.
+        let response = if is_json {
+            serde_json::from_str::<ErrorResponse>(&body)
+                .inspect_err(|error| debug!(%error, "Unrecognised error response"))
+                .ok()
+        } else {
+            None
+        };
+
+        Err(ClientError::from_response(status, &headers, response, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, body: &'static str) -> reqwest::Response {
+        http::Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap()
+            .into()
     }
 
-    fn log_rate_limits(self, started_at: DateTime<Utc>) -> Result<Self> {
-        log_rate_limits(&self, started_at)?;
-        Ok(self)
+    #[tokio::test]
+    async fn unrecognised_json_error_is_classified_by_status() {
+        let err = response(502, r#"{"message": "Bad gateway"#)
+            .parse_error()
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ClientError::Server(_)), "{err:?}");
+        assert!(err.is_transient());
+
+        let err = response(400, "[]").parse_error().await.unwrap_err();
+        assert!(matches!(err, ClientError::Rejected(_)), "{err:?}");
     }
-}
 
-impl std::error::Error for ErrorResponse {
-    fn description(&self) -> &str {
-        &self.detail
+    #[test]
+    fn build_url_appends_to_base() {
+        let base: Uri = "https://bankaccountdata.gocardless.com/".parse().unwrap();
+        assert_eq!(
+            build_url(&base, "/api/v2/token/new/").unwrap(),
+            "https://bankaccountdata.gocardless.com/api/v2/token/new/"
+        );
     }
-}
 
-impl fmt::Display for ErrorResponse {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let ErrorResponse {
-            summary,
-            detail,
-            status_code,
-            other,
-        } = self;
-        write!(
-            f,
-            "Summary: {summary:?}; details: {detail:?}, status_code: {status_code:?}, other: {}",
-            serde_json::to_string(other)
-                .unwrap_or_else(|err| format!("Error rendering other: {err}")),
-        )
+    #[test]
+    fn build_url_keeps_base_path() {
+        let base: Uri = "http://localhost:8080/proxy/".parse().unwrap();
+        assert_eq!(
+            build_url(&base, "/api/v2/institutions/?country=gb").unwrap(),
+            "http://localhost:8080/proxy/api/v2/institutions/?country=gb"
+        );
     }
 }
@@ -1,17 +1,31 @@
-use std::{collections::HashMap, fs, net::SocketAddr, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
 use again::RetryPolicy;
-use chrono::Days;
+use chrono::{DateTime, Days, Utc};
 use clap::Args;
-use color_eyre::{eyre::Context, Result};
-use http::Uri;
+use color_eyre::{
+    eyre::{bail, eyre, Context},
+    Result,
+};
+use http::{uri::Scheme, Uri};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use tokio::task::spawn_blocking;
 use tracing::{instrument, Span};
 use uuid::Uuid;
 
-use crate::{connect::Requisition, files::write_json_atomically};
+use crate::{
+    agreements::AccessScope, connect::Requisition, files::write_json_atomically, lock::FileLock,
+    secrets::SecretSource,
+};
+
+const DEFAULT_API_BASE_URL: &str = "https://bankaccountdata.gocardless.com/";
 
 #[derive(Debug, Clone, Args)]
 pub(crate) struct ConfigArg {
@@ -25,6 +39,16 @@ pub(crate) struct ProviderConfig {
     pub(crate) output: PathBuf,
     pub(crate) history_days: Option<u64>,
     pub(crate) state: PathBuf,
+    // End user agreement terms; clamped to what the institution allows.
+    pub(crate) max_historical_days: Option<u64>,
+    pub(crate) access_valid_for_days: Option<u64>,
+    pub(crate) access_scope: Option<Vec<AccessScope>>,
+    // Maps an account identifier (IBAN, BBAN, masked PAN, resource id or
+    // GoCardless account id) to the directory name to use under `output`.
+    #[serde(default)]
+    pub(crate) account_aliases: HashMap<String, String>,
+    // How long cached account details are trusted before being re-fetched.
+    pub(crate) details_refresh_days: Option<u64>,
 }
 
 #[derive(Debug, Clone, Deserialize, Default)]
@@ -51,14 +75,37 @@ impl RetryConfig {
     }
 }
 
+#[derive(Debug, Clone, Deserialize, Default)]
+pub(crate) struct RateLimitConfig {
+    // Longest we will sleep for an endpoint quota to reset before giving up.
+    pub(crate) max_wait_s: Option<u64>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct ScraperConfig {
     pub(crate) provider: HashMap<String, ProviderConfig>,
     #[serde(default)]
     pub(crate) retries: RetryConfig,
+    #[serde(default)]
+    pub(crate) rate_limits: RateLimitConfig,
+    #[serde(default)]
+    pub(crate) api: ApiConfig,
+    #[serde(default)]
+    pub(crate) secrets: Option<SecretSource>,
     pub(crate) http: HttpListenerConfig,
 }
 
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, Default)]
+pub(crate) struct ApiConfig {
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    base_url: Option<Uri>,
+    // Only honoured when `base_url` points at a loopback host.
+    #[serde(default)]
+    allow_plain_http: bool,
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct HttpListenerConfig {
@@ -73,9 +120,55 @@ impl HttpListenerConfig {
     }
 }
 
+impl ApiConfig {
+    pub(crate) fn base_url(&self) -> Result<Uri> {
+        let Some(base_url) = self.base_url.clone() else {
+            return DEFAULT_API_BASE_URL
+                .parse()
+                .wrap_err("Parse default API base URL");
+        };
+
+        let Some(host) = base_url.host() else {
+            bail!("API base URL missing host: {base_url}");
+        };
+
+        match base_url.scheme() {
+            Some(scheme) if *scheme == Scheme::HTTPS => {}
+            Some(scheme) if *scheme == Scheme::HTTP => {
+                if !self.allow_plain_http {
+                    bail!("Plain HTTP API base URL requires allow_plain_http: {base_url}");
+                }
+                if !is_loopback_host(host) {
+                    bail!("Plain HTTP API base URL only permitted for localhost: {base_url}");
+                }
+            }
+            _ => return Err(eyre!("Unsupported API base URL scheme: {base_url}")),
+        }
+
+        Ok(base_url)
+    }
+}
+
+fn is_loopback_host(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.eq_ignore_ascii_case("localhost")
+        || host
+            .parse::<IpAddr>()
+            .map(|addr| addr.is_loopback())
+            .unwrap_or(false)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct ProviderState {
     pub(crate) requisition_id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) agreement_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) agreement_accepted: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) consent_expires: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_synced: Option<DateTime<Utc>>,
 }
 impl ConfigArg {
     pub(crate) async fn load(&self) -> Result<ScraperConfig> {
@@ -89,8 +182,35 @@ impl ConfigArg {
 }
 
 impl ProviderConfig {
-    pub(crate) fn history_days(&self) -> Days {
-        Days::new(self.history_days.unwrap_or(90))
+    pub(crate) fn history_days(&self) -> u64 {
+        self.history_days.unwrap_or(90)
+    }
+
+    pub(crate) fn details_refresh(&self) -> Days {
+        Days::new(self.details_refresh_days.unwrap_or(7))
+    }
+
+    // Kept next to the state file, eg: `mock-state.json` => `mock-state.ledger.json`.
+    pub(crate) fn ledger_path(&self) -> PathBuf {
+        self.state.with_extension("ledger.json")
+    }
+
+    // Likewise, eg: `mock-state.json` => `mock-state.lock`.
+    pub(crate) fn lock_path(&self) -> PathBuf {
+        self.state.with_extension("lock")
+    }
+
+    /// Held by anything that may update the provider's state, so that (eg:)
+    /// a `sync` from cron cannot trample a `connect` in progress.
+    pub(crate) async fn lock(&self, wait: bool) -> Result<FileLock> {
+        let path = self.lock_path();
+        if wait {
+            return FileLock::acquire(&path).await;
+        }
+
+        FileLock::try_acquire(&path)
+            .await?
+            .ok_or_else(|| eyre!("Provider is in use by another process (lock held on {path:?})"))
     }
 
     pub(crate) async fn write_state(&self, state: &ProviderState) -> Result<()> {
@@ -116,6 +236,54 @@ impl ProviderState {
     pub(crate) fn from_requisition(requisition: &Requisition) -> Self {
         ProviderState {
             requisition_id: requisition.id,
+            agreement_id: requisition.agreement,
+            agreement_accepted: None,
+            consent_expires: None,
+            last_synced: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api(base_url: &str, allow_plain_http: bool) -> ApiConfig {
+        ApiConfig {
+            base_url: Some(base_url.parse().unwrap()),
+            allow_plain_http,
         }
     }
+
+    #[test]
+    fn defaults_to_gocardless() {
+        let base_url = ApiConfig::default().base_url().unwrap();
+        assert_eq!(base_url.to_string(), DEFAULT_API_BASE_URL);
+    }
+
+    #[test]
+    fn accepts_https() {
+        assert!(api("https://proxy.example.com/gc/", false)
+            .base_url()
+            .is_ok());
+    }
+
+    #[test]
+    fn plain_http_needs_opt_in() {
+        assert!(api("http://localhost:8080/", false).base_url().is_err());
+        assert!(api("http://localhost:8080/", true).base_url().is_ok());
+        assert!(api("http://127.0.0.1:8080/", true).base_url().is_ok());
+        assert!(api("http://[::1]:8080/", true).base_url().is_ok());
+    }
+
+    #[test]
+    fn plain_http_only_for_loopback() {
+        assert!(api("http://proxy.example.com/", true).base_url().is_err());
+        assert!(api("http://10.0.0.1/", true).base_url().is_err());
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        assert!(api("ftp://localhost/", true).base_url().is_err());
+    }
 }
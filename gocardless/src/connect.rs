@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
 use askama::Template;
 use axum::{
@@ -9,23 +9,32 @@ use axum::{
     routing::get,
     Router,
 };
+use chrono::{DateTime, Utc};
 use clap::Parser;
 use color_eyre::{
-    eyre::{eyre, Context},
+    eyre::{bail, eyre, Context},
     Report, Result,
 };
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, signal::ctrl_c, time::timeout};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, field, info, instrument, warn, Span};
 use uuid::Uuid;
 
 use crate::{
+    agreements::{create_agreement, record_consent},
     auth::AuthArgs,
     client::BankDataClient,
-    config::{ConfigArg, ProviderState},
+    config::{ConfigArg, ProviderState, ScraperConfig},
+    institutions::fetch_institution,
+    requisitions::{delete_requisition, fetch_requisition},
 };
 
+// How often to check on the requisition when we have no redirect to wait for.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+// How long a headless connect waits for the link, unless told otherwise.
+const DEFAULT_HEADLESS_TIMEOUT_S: u64 = 900;
+
 #[derive(Debug, Parser)]
 pub struct Cmd {
     #[clap(flatten)]
@@ -34,19 +43,41 @@ pub struct Cmd {
     config: ConfigArg,
     #[clap(short = 'p', long = "provider", help = "Provider name")]
     provider: String,
+    #[clap(
+        long = "headless",
+        help = "Poll for the requisition to be linked rather than serving the redirect"
+    )]
+    headless: bool,
+    #[clap(
+        long = "timeout",
+        help = "Seconds to wait for the requisition to be linked [default: 900 with --headless, otherwise no limit]"
+    )]
+    timeout_s: Option<u64>,
+    #[clap(
+        long = "delete-on-abort",
+        help = "Delete the requisition if it is not linked, eg: on timeout or Ctrl-C"
+    )]
+    delete_on_abort: bool,
 }
 
 #[derive(Debug, Serialize)]
 struct RequisitionReq {
     institution_id: String,
     redirect: String,
+    agreement: Uuid,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct Requisition {
     pub(crate) id: Uuid,
+    #[serde(default)]
+    pub(crate) created: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub(crate) institution_id: String,
     pub(crate) link: String,
     pub(crate) status: RequisitionStatus,
     pub(crate) accounts: Vec<Uuid>,
+    #[serde(default)]
+    pub(crate) agreement: Option<Uuid>,
     #[serde(flatten)]
     pub(crate) other: serde_json::Value,
 }
@@ -83,7 +114,7 @@ impl Cmd {
     #[instrument("auth", skip_all, fields(provider = %self.provider, institution_id, requisition_id))]
     pub(crate) async fn run(&self) -> Result<()> {
         let config = self.config.load().await?;
-        let token = self.auth.load_token().await?;
+        let client = self.auth.client(&config).await?;
 
         let Some(provider_config) = config.provider.get(&self.provider) else {
             return Err(eyre!("Unrecognised provider: {}", self.provider));
@@ -91,12 +122,16 @@ impl Cmd {
 
         Span::current().record("institution_id", &provider_config.institution_id);
 
-        let client = BankDataClient::new(token, &config.retries);
+        let _lock = provider_config.lock(false).await?;
 
-        let cnx = CancellationToken::new();
-        let listener = TcpListener::bind(config.http.bind_address)
-            .await
-            .with_context(|| format!("Bind to address: {}", config.http.bind_address))?;
+        let listener = if self.headless {
+            None
+        } else {
+            let listener = TcpListener::bind(config.http.bind_address)
+                .await
+                .with_context(|| format!("Bind to address: {}", config.http.bind_address))?;
+            Some(listener)
+        };
 
         let base_url = config
             .http
@@ -105,9 +140,13 @@ impl Cmd {
             .build()
             .context("Build base URI")?;
 
+        let institution = fetch_institution(&client, &provider_config.institution_id).await?;
+        let agreement = create_agreement(&client, provider_config, &institution).await?;
+
         let req = RequisitionReq {
             institution_id: provider_config.institution_id.clone(),
             redirect: base_url.to_string(),
+            agreement: agreement.id,
         };
 
         debug!(?req);
@@ -120,45 +159,152 @@ impl Cmd {
 
         debug!(?requisition, "Got requisition");
 
-        let app = Router::new().merge(routes(cnx.clone(), client.clone(), requisition.id));
-
-        let auth_url = config
-            .http
-            .client_facing_url_builder()
-            .path_and_query(format!(
-                "?{}",
-                serde_urlencoded::to_string(RequisitionCallbackQuery { id: requisition.id })
-                    .context("encode query")?,
-            ))
-            .build()
-            .context("Build auth URI")?;
-
-        println!("Go to link: {}", auth_url);
-        info!("Awaiting response");
+        let linked = async {
+            match listener {
+                Some(listener) => await_redirect(&config, listener, &client, requisition.id).await,
+                None => self.poll_until_linked(&client, &requisition).await,
+            }
+        };
 
-        axum::serve(listener, app)
-            .with_graceful_shutdown(cnx.clone().cancelled_owned())
-            .await
-            .context("Running server")?;
+        let linked = async {
+            let Some(timeout_s) = self.timeout_s() else {
+                return linked.await;
+            };
+            timeout(Duration::from_secs(timeout_s), linked)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(eyre!(
+                        "Timed out after {}s waiting for requisition to be linked",
+                        timeout_s
+                    ))
+                })
+        };
 
-        let requisition = client
-            .get::<Requisition>(&format!("/api/v2/requisitions/{}/", requisition.id))
-            .await?;
+        let result = tokio::select! {
+            result = linked => result,
+            _ = ctrl_c() => Err(eyre!("Interrupted waiting for requisition to be linked")),
+        };
 
-        debug!(?requisition, "Got requisition",);
+        let requisition = match result {
+            Ok(requisition) => requisition,
+            Err(error) => {
+                self.abandon(&client, requisition.id).await;
+                return Err(error);
+            }
+        };
 
-        let state = ProviderState::from_requisition(&requisition);
+        let mut state = ProviderState::from_requisition(&requisition);
+        record_consent(&client, &mut state).await?;
 
         provider_config.write_state(&state).await?;
 
         Ok(())
     }
+
+    // Nobody may be watching a headless connect, so it gives up by default;
+    // the browser flow waits for as long as it takes unless asked not to.
+    fn timeout_s(&self) -> Option<u64> {
+        self.timeout_s
+            .or(self.headless.then_some(DEFAULT_HEADLESS_TIMEOUT_S))
+    }
+
+    #[instrument(skip_all)]
+    async fn poll_until_linked(
+        &self,
+        client: &BankDataClient,
+        requisition: &Requisition,
+    ) -> Result<Requisition> {
+        println!("Go to link: {}", requisition.link);
+        println!(
+            "{}",
+            terminal_qr::render(&requisition.link).wrap_err("Encode QR code")?
+        );
+        info!("Polling for requisition to be linked");
+
+        loop {
+            let requisition = fetch_requisition(client, requisition.id).await?;
+            if requisition.is_final() {
+                return requisition.into_linked();
+            }
+            debug!(status=%requisition.status, "Still waiting");
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    // Otherwise the requisition lingers until it expires.
+    async fn abandon(&self, client: &BankDataClient, requisition_id: Uuid) {
+        if !self.delete_on_abort {
+            warn!(%requisition_id, "Leaving requisition behind; see `requisitions delete`");
+            return;
+        }
+
+        if let Err(error) = delete_requisition(client, requisition_id).await {
+            warn!(%requisition_id, ?error, "Could not delete requisition");
+        }
+    }
+}
+
+async fn await_redirect(
+    config: &ScraperConfig,
+    listener: TcpListener,
+    client: &BankDataClient,
+    requisition_id: Uuid,
+) -> Result<Requisition> {
+    let cnx = CancellationToken::new();
+    let app = Router::new().merge(routes(cnx.clone(), client.clone(), requisition_id));
+
+    let auth_url = config
+        .http
+        .client_facing_url_builder()
+        .path_and_query(format!(
+            "?{}",
+            serde_urlencoded::to_string(RequisitionCallbackQuery { id: requisition_id })
+                .context("encode query")?,
+        ))
+        .build()
+        .context("Build auth URI")?;
+
+    println!("Go to link: {}", auth_url);
+    info!("Awaiting response");
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(cnx.clone().cancelled_owned())
+        .await
+        .context("Running server")?;
+
+    let requisition = fetch_requisition(client, requisition_id).await?;
+
+    debug!(?requisition, "Got requisition",);
+
+    requisition.into_linked()
 }
 
 impl Requisition {
     pub(crate) fn is_linked(&self) -> bool {
         self.status == RequisitionStatus::Linked
     }
+
+    // Whether the requisition is done changing, successfully or otherwise.
+    fn is_final(&self) -> bool {
+        matches!(
+            self.status,
+            RequisitionStatus::Linked | RequisitionStatus::Rejected | RequisitionStatus::Expired
+        )
+    }
+
+    fn into_linked(self) -> Result<Requisition> {
+        match self.status {
+            RequisitionStatus::Linked => Ok(self),
+            RequisitionStatus::Rejected => bail!(
+                "Requisition {} was rejected: verification failed or the credentials were wrong",
+                self.id
+            ),
+            RequisitionStatus::Expired => {
+                bail!("Requisition {} expired before it was linked", self.id)
+            }
+            status => bail!("Requisition {} was not linked: {}", self.id, status),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -242,7 +388,15 @@ async fn handle_redirect(
             info!("Received confirmation");
             state.cnx.cancel();
         }
-        status => warn!(?status, "Other requisition status"),
+        RequisitionStatus::Rejected | RequisitionStatus::Expired => {
+            warn!(status=%requisition.status, "Requisition will not be linked");
+            state.cnx.cancel();
+            return Ok((
+                StatusCode::OK,
+                format!("Requisition {}; see the console", requisition.status),
+            )
+                .into_response());
+        }
     }
 
     Ok((StatusCode::OK, "Ok").into_response())
@@ -0,0 +1,301 @@
+use std::{error::Error as StdError, fmt, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+use serde::Deserialize;
+
+use crate::ratelimit::QuotaScope;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ErrorResponse {
+    #[serde(default)]
+    pub(crate) summary: String,
+    #[serde(default)]
+    pub(crate) detail: String,
+    #[serde(default)]
+    pub(crate) status_code: u16,
+    #[serde(flatten)]
+    pub(crate) other: serde_json::Value,
+}
+
+#[derive(Debug)]
+pub(crate) struct ErrorDetails {
+    pub(crate) status: StatusCode,
+    pub(crate) response: Option<ErrorResponse>,
+    pub(crate) body: String,
+}
+
+#[derive(Debug)]
+pub(crate) enum ClientError {
+    /// Our access token was rejected; a fresh one is needed.
+    AuthExpired(ErrorDetails),
+    NotFound(ErrorDetails),
+    /// Either refused locally from what we know of the quotas, or reported
+    /// by the server. Only worth retrying when we know how long to wait.
+    RateLimited {
+        scope: Option<QuotaScope>,
+        until: Option<DateTime<Utc>>,
+        retry_after: Option<Duration>,
+    },
+    InstitutionUnavailable(ErrorDetails),
+    Server(ErrorDetails),
+    /// Any other client error, eg: a malformed request.
+    Rejected(ErrorDetails),
+    Transport(reqwest::Error),
+    Decode(Box<dyn StdError + Send + Sync + 'static>),
+}
+
+impl ClientError {
+    pub(crate) fn from_response(
+        status: StatusCode,
+        headers: &HeaderMap,
+        response: Option<ErrorResponse>,
+        body: String,
+    ) -> Self {
+        let mentions_institution = response.as_ref().is_some_and(|r| {
+            r.summary.to_lowercase().contains("institution")
+                || r.detail.to_lowercase().contains("institution")
+        });
+        let details = ErrorDetails {
+            status,
+            response,
+            body,
+        };
+
+        match status {
+            StatusCode::UNAUTHORIZED => ClientError::AuthExpired(details),
+            StatusCode::NOT_FOUND => ClientError::NotFound(details),
+            StatusCode::TOO_MANY_REQUESTS => ClientError::RateLimited {
+                scope: None,
+                until: None,
+                retry_after: retry_after(headers),
+            },
+            StatusCode::SERVICE_UNAVAILABLE => ClientError::InstitutionUnavailable(details),
+            status
+                if mentions_institution
+                    && (status.is_server_error() || status == StatusCode::CONFLICT) =>
+            {
+                ClientError::InstitutionUnavailable(details)
+            }
+            status if status.is_server_error() => ClientError::Server(details),
+            _ => ClientError::Rejected(details),
+        }
+    }
+
+    pub(crate) fn decode(err: impl Into<Box<dyn StdError + Send + Sync + 'static>>) -> Self {
+        ClientError::Decode(err.into())
+    }
+
+    /// Short `Retry-After` delays are already waited out when sending, so
+    /// being rate limited is not transient by this point.
+    pub(crate) fn is_transient(&self) -> bool {
+        match self {
+            ClientError::InstitutionUnavailable(_)
+            | ClientError::Server(_)
+            | ClientError::Transport(_) => true,
+            ClientError::AuthExpired(_)
+            | ClientError::NotFound(_)
+            | ClientError::RateLimited { .. }
+            | ClientError::Rejected(_)
+            | ClientError::Decode(_) => false,
+        }
+    }
+
+    /// Whether we know the request never reached the server.
+    pub(crate) fn is_unsent(&self) -> bool {
+        match self {
+            ClientError::Transport(err) => err.is_connect(),
+            _ => false,
+        }
+    }
+}
+
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    (at.to_utc() - Utc::now()).to_std().ok()
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(value: reqwest::Error) -> Self {
+        if value.is_decode() {
+            ClientError::Decode(value.into())
+        } else {
+            ClientError::Transport(value)
+        }
+    }
+}
+
+impl StdError for ClientError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            ClientError::Transport(err) => Some(err),
+            ClientError::Decode(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::AuthExpired(details) => write!(f, "Authentication expired: {details}"),
+            ClientError::NotFound(details) => write!(f, "Not found: {details}"),
+            ClientError::RateLimited {
+                scope: Some(scope),
+                until: Some(until),
+                ..
+            } => write!(f, "Rate limit quota for {scope} exhausted until {until}"),
+            ClientError::RateLimited {
+                retry_after: Some(retry_after),
+                ..
+            } => write!(f, "Rate limited; retry after {retry_after:?}"),
+            ClientError::RateLimited { .. } => write!(f, "Rate limited"),
+            ClientError::InstitutionUnavailable(details) => {
+                write!(f, "Institution unavailable: {details}")
+            }
+            ClientError::Server(details) => write!(f, "Server error: {details}"),
+            ClientError::Rejected(details) => write!(f, "Request rejected: {details}"),
+            ClientError::Transport(err) => write!(f, "Transport error: {err}"),
+            ClientError::Decode(err) => write!(f, "Decoding response: {err}"),
+        }
+    }
+}
+
+impl fmt::Display for ErrorDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ErrorDetails {
+            status,
+            response,
+            body,
+        } = self;
+        match response {
+            Some(response) => write!(f, "{response}"),
+            None => write!(f, "code: {status}; content: {body:?}"),
+        }
+    }
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ErrorResponse {
+            summary,
+            detail,
+            status_code,
+            other,
+        } = self;
+        write!(
+            f,
+            "Summary: {summary:?}; details: {detail:?}, status_code: {status_code:?}, other: {}",
+            serde_json::to_string(other)
+                .unwrap_or_else(|err| format!("Error rendering other: {err}")),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    fn error_response(summary: &str) -> Option<ErrorResponse> {
+        Some(ErrorResponse {
+            summary: summary.to_owned(),
+            detail: String::new(),
+            status_code: 0,
+            other: serde_json::Value::Null,
+        })
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(retry_after(&headers("30")), Some(Duration::from_secs(30)));
+        assert_eq!(retry_after(&headers(" 5 ")), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn parses_retry_after_date() {
+        let at = Utc::now() + chrono::Duration::seconds(120);
+        let delay = retry_after(&headers(&at.to_rfc2822())).unwrap();
+        assert!(delay > Duration::from_secs(100) && delay <= Duration::from_secs(120));
+    }
+
+    #[test]
+    fn ignores_past_or_garbled_retry_after() {
+        let past = Utc::now() - chrono::Duration::seconds(120);
+        assert_eq!(retry_after(&headers(&past.to_rfc2822())), None);
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn classifies_statuses() {
+        let classify = |status, summary: &str| {
+            ClientError::from_response(
+                status,
+                &headers("1"),
+                error_response(summary),
+                String::new(),
+            )
+        };
+
+        assert!(matches!(
+            classify(StatusCode::UNAUTHORIZED, ""),
+            ClientError::AuthExpired(_)
+        ));
+        assert!(matches!(
+            classify(StatusCode::NOT_FOUND, ""),
+            ClientError::NotFound(_)
+        ));
+        assert!(matches!(
+            classify(StatusCode::TOO_MANY_REQUESTS, ""),
+            ClientError::RateLimited {
+                retry_after: Some(_),
+                ..
+            }
+        ));
+        assert!(matches!(
+            classify(StatusCode::SERVICE_UNAVAILABLE, ""),
+            ClientError::InstitutionUnavailable(_)
+        ));
+        assert!(matches!(
+            classify(StatusCode::CONFLICT, "Institution service unavailable"),
+            ClientError::InstitutionUnavailable(_)
+        ));
+        assert!(matches!(
+            classify(StatusCode::INTERNAL_SERVER_ERROR, ""),
+            ClientError::Server(_)
+        ));
+        assert!(matches!(
+            classify(StatusCode::BAD_REQUEST, ""),
+            ClientError::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn only_server_side_failures_are_transient() {
+        let classify =
+            |status| ClientError::from_response(status, &HeaderMap::new(), None, String::new());
+
+        assert!(classify(StatusCode::BAD_GATEWAY).is_transient());
+        assert!(classify(StatusCode::SERVICE_UNAVAILABLE).is_transient());
+        assert!(!classify(StatusCode::UNAUTHORIZED).is_transient());
+        assert!(!classify(StatusCode::NOT_FOUND).is_transient());
+        assert!(!classify(StatusCode::TOO_MANY_REQUESTS).is_transient());
+        assert!(!classify(StatusCode::BAD_GATEWAY).is_unsent());
+    }
+}
@@ -1,12 +1,12 @@
 use std::{
-    fs::Permissions,
-    io::{BufWriter, Write},
+    fs::{self, Permissions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
     os::unix::fs::PermissionsExt,
     path::Path,
 };
 
 use color_eyre::{eyre::Context, Result};
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::task::spawn_blocking;
 use tracing::{debug, instrument, Span};
 
@@ -75,3 +75,52 @@ pub(crate) async fn write_json_lines(
 
     Ok(())
 }
+
+/// Reads back a file written by [`write_json_atomically`], or `None` if it
+/// does not exist yet.
+#[instrument(skip_all, fields(?path))]
+pub(crate) async fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    let buf = match tokio::fs::read(path).await {
+        Ok(buf) => buf,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).wrap_err_with(|| format!("Read file: {path:?}")),
+    };
+
+    let data = serde_json::from_slice(&buf).wrap_err_with(|| format!("Parse file: {path:?}"))?;
+
+    Ok(Some(data))
+}
+
+/// Reads back a file written by [`write_json_lines`], treating a missing file
+/// as empty.
+#[instrument(skip_all, fields(?path))]
+pub(crate) async fn read_json_lines<T: DeserializeOwned + Send + 'static>(
+    path: &Path,
+) -> Result<Vec<T>> {
+    let span = Span::current();
+    let path = path.to_owned();
+    spawn_blocking(move || -> Result<_> {
+        let _entered = span.enter();
+        let f = match fs::File::open(&path) {
+            Ok(f) => f,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err).wrap_err_with(|| format!("Open file: {path:?}")),
+        };
+
+        let mut data = Vec::new();
+        for (lineno, line) in BufReader::new(f).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let datum = serde_json::from_str(&line)
+                .wrap_err_with(|| format!("Parse line {} of {path:?}", lineno + 1))?;
+            data.push(datum);
+        }
+
+        debug!(count = data.len(), "Read data from file");
+
+        Ok(data)
+    })
+    .await?
+}
@@ -1,9 +1,17 @@
+use std::io::{self, Write};
+
 use clap::Parser;
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
-use tracing::{info, instrument};
+use serde_with::serde_as;
+use tracing::{debug, instrument};
 
-use crate::{auth::AuthArgs, client::BankDataClient, config::ConfigArg};
+use crate::{
+    auth::AuthArgs,
+    client::BankDataClient,
+    config::ConfigArg,
+    table::{write_table, OutputFormat},
+};
 
 #[derive(Debug, Parser)]
 pub struct Cmd {
@@ -11,34 +19,109 @@ pub struct Cmd {
     auth: AuthArgs,
     #[clap(flatten)]
     config: ConfigArg,
+    #[clap(long = "country", default_value = "gb", help = "ISO 3166 country code")]
+    country: String,
+    #[clap(
+        short = 'q',
+        long = "search",
+        help = "Only show institutions whose name or BIC contains this"
+    )]
+    search: Option<String>,
+    #[clap(long = "id", help = "Show a single institution", conflicts_with_all = ["country", "search"])]
+    id: Option<String>,
+    #[clap(long = "format", value_enum, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
 }
 
+#[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
-struct Institution {
-    id: String,
-    name: String,
-    bic: String,
-    transaction_total_days: String,
-    max_access_valid_for_days: String,
-    countries: Vec<String>,
-    logo: String,
+pub(crate) struct Institution {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) bic: String,
+    // The API hands these back as strings.
+    #[serde_as(as = "serde_with::PickFirst<(_, serde_with::DisplayFromStr)>")]
+    pub(crate) transaction_total_days: u64,
+    #[serde_as(as = "serde_with::PickFirst<(_, serde_with::DisplayFromStr)>")]
+    pub(crate) max_access_valid_for_days: u64,
+    pub(crate) countries: Vec<String>,
+    pub(crate) logo: String,
 }
 
 impl Cmd {
     #[instrument("institutions", skip_all)]
     pub(crate) async fn run(&self) -> Result<()> {
-        let token = self.auth.load_token().await?;
-
         let config = self.config.load().await?;
 
-        let client = BankDataClient::new(token, &config.retries);
+        let client = self.auth.client(&config).await?;
+
+        let data = if let Some(id) = &self.id {
+            vec![fetch_institution(&client, id).await?]
+        } else {
+            let mut data = client
+                .get::<Vec<Institution>>(&format!(
+                    "/api/v2/institutions/?{}",
+                    serde_urlencoded::to_string([("country", &self.country)])?
+                ))
+                .await?;
+            if let Some(search) = &self.search {
+                data.retain(|institution| institution.matches(search));
+            }
+            data
+        };
 
-        let data = client
-            .get::<Vec<Institution>>("/api/v2/institutions/?country=gb")
-            .await?;
+        debug!(count = data.len(), "Got institutions");
 
-        info!("Institutions: {}", serde_json::to_string_pretty(&data)?);
+        let mut out = io::stdout().lock();
+        match self.format {
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, &data)?;
+                writeln!(out)?;
+            }
+            OutputFormat::Table => write_institutions(&mut out, &data)?,
+        }
 
         Ok(())
     }
 }
+
+#[instrument(skip(client))]
+pub(crate) async fn fetch_institution(
+    client: &BankDataClient,
+    institution_id: &str,
+) -> Result<Institution> {
+    let institution = client
+        .get::<Institution>(&format!("/api/v2/institutions/{}/", institution_id))
+        .await?;
+    Ok(institution)
+}
+
+impl Institution {
+    fn matches(&self, search: &str) -> bool {
+        let search = search.to_lowercase();
+        self.name.to_lowercase().contains(&search) || self.bic.to_lowercase().contains(&search)
+    }
+}
+
+fn write_institutions(out: &mut impl Write, data: &[Institution]) -> Result<()> {
+    let rows = data
+        .iter()
+        .map(|i| {
+            [
+                i.id.clone(),
+                i.name.clone(),
+                i.bic.clone(),
+                i.transaction_total_days.to_string(),
+                i.max_access_valid_for_days.to_string(),
+                i.countries.join(","),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    write_table(
+        out,
+        ["ID", "NAME", "BIC", "HISTORY", "ACCESS", "COUNTRIES"],
+        &rows,
+    )
+}
@@ -0,0 +1,142 @@
+use std::{collections::BTreeMap, io, path::Path};
+
+use chrono::{DateTime, Duration, Utc};
+use color_eyre::{eyre::Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+use uuid::Uuid;
+
+use crate::{
+    files::write_json_atomically,
+    ratelimit::{EndpointClass, Quota},
+};
+
+// GoCardless allows (at least) four successful calls per account endpoint
+// each day; used when we have not yet seen the headers for an endpoint.
+const DEFAULT_DAILY_ACCOUNT_CALLS: u32 = 4;
+const DEFAULT_WINDOW: Duration = Duration::days(1);
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Ledger {
+    #[serde(default)]
+    accounts: BTreeMap<Uuid, BTreeMap<EndpointClass, LedgerEntry>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LedgerEntry {
+    calls: u32,
+    window_started_at: DateTime<Utc>,
+    last_call_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    limit: Option<i64>,
+    #[serde(default)]
+    remaining: Option<i64>,
+    #[serde(default)]
+    reset_at: Option<DateTime<Utc>>,
+}
+
+impl Ledger {
+    #[instrument(skip_all, fields(?path))]
+    pub(crate) async fn load(path: &Path) -> Result<Ledger> {
+        let buf = match tokio::fs::read(path).await {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("No ledger found; starting afresh");
+                return Ok(Ledger::default());
+            }
+            Err(err) => return Err(err).wrap_err_with(|| format!("Read ledger: {path:?}")),
+        };
+
+        let ledger =
+            serde_json::from_slice(&buf).wrap_err_with(|| format!("Parse ledger: {path:?}"))?;
+
+        Ok(ledger)
+    }
+
+    pub(crate) async fn store(&self, path: &Path) -> Result<()> {
+        write_json_atomically(path, self.clone()).await
+    }
+
+    /// Returns when the budget for this account endpoint resets if we have
+    /// already spent it, or `None` if we can afford another call.
+    pub(crate) fn exhausted_until(
+        &self,
+        account_id: Uuid,
+        class: EndpointClass,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let entry = self
+            .accounts
+            .get(&account_id)
+            .and_then(|endpoints| endpoints.get(&class))
+            .filter(|entry| !entry.is_stale(now))?;
+
+        let exhausted = match entry.remaining {
+            Some(remaining) => remaining <= 0,
+            None => entry.calls >= DEFAULT_DAILY_ACCOUNT_CALLS,
+        };
+
+        exhausted.then(|| entry.resets_at())
+    }
+
+    /// Records the outcome of a call, along with the quota the server
+    /// reported, if any.
+    pub(crate) fn record(
+        &mut self,
+        account_id: Uuid,
+        class: EndpointClass,
+        succeeded: bool,
+        quota: Option<Quota>,
+        now: DateTime<Utc>,
+    ) {
+        let entry = self
+            .accounts
+            .entry(account_id)
+            .or_default()
+            .entry(class)
+            .or_insert_with(|| LedgerEntry::new(now));
+
+        if entry.is_stale(now) {
+            *entry = LedgerEntry::new(now);
+        }
+
+        if succeeded {
+            entry.calls += 1;
+            entry.last_call_at = Some(now);
+            entry.remaining = entry.remaining.map(|remaining| remaining - 1);
+        }
+
+        if let Some(Quota {
+            limit,
+            remaining,
+            reset_at,
+        }) = quota
+        {
+            entry.limit = Some(limit);
+            entry.remaining = Some(remaining);
+            entry.reset_at = Some(reset_at);
+        }
+    }
+}
+
+impl LedgerEntry {
+    fn new(now: DateTime<Utc>) -> Self {
+        LedgerEntry {
+            calls: 0,
+            window_started_at: now,
+            last_call_at: None,
+            limit: None,
+            remaining: None,
+            reset_at: None,
+        }
+    }
+
+    fn resets_at(&self) -> DateTime<Utc> {
+        self.reset_at
+            .unwrap_or(self.window_started_at + DEFAULT_WINDOW)
+    }
+
+    fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        self.resets_at() <= now
+    }
+}
@@ -1,12 +1,25 @@
 mod accounts;
+mod agreements;
 mod auth;
 mod client;
 mod config;
 mod connect;
+mod error;
 mod files;
 mod institutions;
+mod ledger;
+mod lock;
+mod ratelimit;
+mod requisitions;
+mod secrets;
+mod status;
+mod summary;
 mod sync;
+mod table;
 mod transactions;
+mod transitions;
+
+use std::process::ExitCode;
 
 use clap::Parser;
 use color_eyre::Result;
@@ -15,17 +28,21 @@ use color_eyre::Result;
 pub enum Command {
     Institutions(institutions::Cmd),
     Connect(connect::Cmd),
+    Requisitions(requisitions::Cmd),
+    Status(status::Cmd),
     Sync(sync::Cmd),
 }
 
 impl Command {
-    pub async fn run(&self) -> Result<()> {
+    pub async fn run(&self) -> Result<ExitCode> {
         match self {
             Command::Institutions(cmd) => cmd.run().await?,
             Command::Connect(cmd) => cmd.run().await?,
-            Command::Sync(cmd) => cmd.run().await?,
+            Command::Requisitions(cmd) => cmd.run().await?,
+            Command::Status(cmd) => cmd.run().await?,
+            Command::Sync(cmd) => return cmd.run().await,
         }
 
-        Ok(())
+        Ok(ExitCode::SUCCESS)
     }
 }
@@ -0,0 +1,86 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+use color_eyre::{eyre::Context, Result};
+use tokio::task::spawn_blocking;
+use tracing::{debug, info, instrument, Span};
+
+/// An advisory `flock(2)` lock on a file, held until this is dropped. Only
+/// protects against other processes that also take the lock.
+#[derive(Debug)]
+pub(crate) struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Takes the lock, waiting for any other holder to release it.
+    #[instrument(skip_all, fields(?path))]
+    pub(crate) async fn acquire(path: &Path) -> Result<FileLock> {
+        let span = Span::current();
+        let path = path.to_owned();
+        spawn_blocking(move || -> Result<_> {
+            let _entered = span.enter();
+            let file = open(&path)?;
+            match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    info!("Waiting for another process to release lock");
+                    flock(&file, libc::LOCK_EX).wrap_err_with(|| format!("Lock file: {path:?}"))?;
+                }
+                Err(err) => return Err(err).wrap_err_with(|| format!("Lock file: {path:?}")),
+            }
+            debug!("Acquired lock");
+            Ok(FileLock { _file: file })
+        })
+        .await?
+    }
+
+    /// Takes the lock if nobody else holds it, or returns `None`.
+    #[instrument(skip_all, fields(?path))]
+    pub(crate) async fn try_acquire(path: &Path) -> Result<Option<FileLock>> {
+        let span = Span::current();
+        let path = path.to_owned();
+        spawn_blocking(move || -> Result<_> {
+            let _entered = span.enter();
+            let file = open(&path)?;
+            match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+                Ok(()) => {
+                    debug!("Acquired lock");
+                    Ok(Some(FileLock { _file: file }))
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+                Err(err) => Err(err).wrap_err_with(|| format!("Lock file: {path:?}")),
+            }
+        })
+        .await?
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).wrap_err_with(|| format!("Creating parent: {parent:?}"))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .wrap_err_with(|| format!("Open lock file: {path:?}"))
+}
+
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        // SAFETY: `file` owns the descriptor and keeps it open for the call.
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
@@ -1,18 +1,20 @@
+use std::process::ExitCode;
+
 use clap::Parser;
 use color_eyre::Result;
 
 use gc_scraper::Command;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     setup_logging()?;
     color_eyre::install()?;
 
     let cmd = Command::parse();
 
-    cmd.run().await?;
+    let code = cmd.run().await?;
 
-    Ok(())
+    Ok(code)
 }
 
 fn setup_logging() -> Result<()> {
@@ -0,0 +1,519 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, trace, warn};
+use uuid::Uuid;
+
+use crate::{config::RateLimitConfig, error::ClientError};
+
+const HTTP_X_RATELIMIT_LIMIT: &str = "HTTP_X_RATELIMIT_LIMIT";
+const HTTP_X_RATELIMIT_REMAINING: &str = "HTTP_X_RATELIMIT_REMAINING";
+const HTTP_X_RATELIMIT_RESET: &str = "HTTP_X_RATELIMIT_RESET";
+const HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_LIMIT: &str = "HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_LIMIT";
+const HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING: &str =
+    "HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING";
+const HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET: &str = "HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET";
+
+const DEFAULT_MAX_WAIT: Duration = Duration::seconds(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EndpointClass {
+    Token,
+    Institutions,
+    Agreements,
+    Requisitions,
+    Account,
+    AccountDetails,
+    AccountBalances,
+    AccountTransactions,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum QuotaScope {
+    Endpoint(EndpointClass),
+    Account(Uuid, EndpointClass),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Quota {
+    pub(crate) limit: i64,
+    pub(crate) remaining: i64,
+    pub(crate) reset_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    quotas: Arc<Mutex<HashMap<QuotaScope, Quota>>>,
+    max_wait: Duration,
+}
+
+impl EndpointClass {
+    pub(crate) fn classify(path: &str) -> (EndpointClass, Option<Uuid>) {
+        let path = path.split('?').next().unwrap_or_default();
+        let segments = path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .skip_while(|s| *s != "v2")
+            .skip(1)
+            .collect::<Vec<_>>();
+
+        match segments.as_slice() {
+            ["token", ..] => (EndpointClass::Token, None),
+            ["institutions", ..] => (EndpointClass::Institutions, None),
+            ["agreements", ..] => (EndpointClass::Agreements, None),
+            ["requisitions", ..] => (EndpointClass::Requisitions, None),
+            ["accounts", id, rest @ ..] => {
+                let class = match rest {
+                    [] => EndpointClass::Account,
+                    ["details", ..] => EndpointClass::AccountDetails,
+                    ["balances", ..] => EndpointClass::AccountBalances,
+                    ["transactions", ..] => EndpointClass::AccountTransactions,
+                    _ => EndpointClass::Other,
+                };
+                (class, id.parse().ok())
+            }
+            _ => (EndpointClass::Other, None),
+        }
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: &RateLimitConfig) -> Self {
+        let max_wait = config
+            .max_wait_s
+            .map(|s| Duration::seconds(s as i64))
+            .unwrap_or(DEFAULT_MAX_WAIT);
+        RateLimiter {
+            quotas: Default::default(),
+            max_wait,
+        }
+    }
+
+    /// Reserves a call against the quotas known for `path`, waiting for the
+    /// endpoint quota to reset if that is due within the configured maximum
+    /// wait. Account quotas are daily, so those are refused outright.
+    pub(crate) async fn acquire(&self, path: &str) -> Result<(), ClientError> {
+        let (class, account_id) = EndpointClass::classify(path);
+        let endpoint_scope = QuotaScope::Endpoint(class);
+        let account_scope = account_id.map(|id| QuotaScope::Account(id, class));
+
+        loop {
+            let now = Utc::now();
+            let wait_until = {
+                let mut quotas = self.quotas.lock().expect("unpoisoned quotas");
+                quotas.retain(|_, quota| quota.reset_at > now);
+
+                if let Some(until) = account_scope.and_then(|s| exhausted_until(&quotas, s)) {
+                    return Err(ClientError::RateLimited {
+                        scope: account_scope,
+                        until: Some(until),
+                        retry_after: None,
+                    });
+                }
+
+                match exhausted_until(&quotas, endpoint_scope) {
+                    Some(until) if until - now > self.max_wait => {
+                        return Err(ClientError::RateLimited {
+                            scope: Some(endpoint_scope),
+                            until: Some(until),
+                            retry_after: None,
+                        });
+                    }
+                    Some(until) => Some(until),
+                    None => {
+                        for scope in [Some(endpoint_scope), account_scope].into_iter().flatten() {
+                            if let Some(quota) = quotas.get_mut(&scope) {
+                                quota.remaining -= 1;
+                            }
+                        }
+                        None
+                    }
+                }
+            };
+
+            let Some(until) = wait_until else {
+                return Ok(());
+            };
+
+            info!(scope=%endpoint_scope, %until, "Rate limit reached; waiting for reset");
+            tokio::time::sleep((until - now).to_std().unwrap_or_default()).await;
+        }
+    }
+
+    pub(crate) fn account_quota(&self, account_id: Uuid, class: EndpointClass) -> Option<Quota> {
+        let quotas = self.quotas.lock().expect("unpoisoned quotas");
+        quotas.get(&QuotaScope::Account(account_id, class)).copied()
+    }
+
+    pub(crate) fn max_wait(&self) -> Duration {
+        self.max_wait
+    }
+
+    /// Returns the most specific exhausted quota that applies to `path`.
+    pub(crate) fn exhausted(&self, path: &str) -> Option<(QuotaScope, DateTime<Utc>)> {
+        let (class, account_id) = EndpointClass::classify(path);
+        let quotas = self.quotas.lock().expect("unpoisoned quotas");
+        [
+            account_id.map(|id| QuotaScope::Account(id, class)),
+            Some(QuotaScope::Endpoint(class)),
+        ]
+        .into_iter()
+        .flatten()
+        .find_map(|scope| exhausted_until(&quotas, scope).map(|until| (scope, until)))
+    }
+
+    /// Updates the known quotas from the response headers.
+    pub(crate) fn record(
+        &self,
+        path: &str,
+        headers: &HeaderMap,
+        started_at: DateTime<Utc>,
+    ) -> Result<(), ClientError> {
+        let (class, account_id) = EndpointClass::classify(path);
+        let endpoint_scope = QuotaScope::Endpoint(class);
+        let account_scope = account_id.map(|id| QuotaScope::Account(id, class));
+
+        let endpoint_quota = parse_quota(
+            headers,
+            started_at,
+            [
+                HTTP_X_RATELIMIT_LIMIT,
+                HTTP_X_RATELIMIT_REMAINING,
+                HTTP_X_RATELIMIT_RESET,
+            ],
+        )?;
+        match endpoint_quota {
+            Some(Quota {
+                limit,
+                remaining,
+                reset_at,
+            }) => debug!(%limit, %remaining, %reset_at, "Rate limit status"),
+            None => warn!(header=%HTTP_X_RATELIMIT_LIMIT, "rate limit header missing"),
+        }
+
+        let account_quota = parse_quota(
+            headers,
+            started_at,
+            [
+                HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_LIMIT,
+                HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING,
+                HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET,
+            ],
+        )?;
+        if let Some(Quota {
+            limit,
+            remaining,
+            reset_at,
+        }) = account_quota
+        {
+            debug!(%limit, %remaining, %reset_at, "Account rate limit status");
+        }
+
+        let mut quotas = self.quotas.lock().expect("unpoisoned quotas");
+        if let Some(quota) = endpoint_quota {
+            quotas.insert(endpoint_scope, quota);
+        }
+        if let (Some(scope), Some(quota)) = (account_scope, account_quota) {
+            quotas.insert(scope, quota);
+        }
+
+        Ok(())
+    }
+}
+
+fn exhausted_until(
+    quotas: &HashMap<QuotaScope, Quota>,
+    scope: QuotaScope,
+) -> Option<DateTime<Utc>> {
+    quotas
+        .get(&scope)
+        .filter(|quota| quota.remaining <= 0)
+        .map(|quota| quota.reset_at)
+}
+
+fn parse_quota(
+    headers: &HeaderMap,
+    started_at: DateTime<Utc>,
+    [limit, remaining, reset]: [&str; 3],
+) -> Result<Option<Quota>, ClientError> {
+    let (Some(limit), Some(remaining), Some(reset)) = (
+        maybe_parse_header(headers, limit)?,
+        maybe_parse_header(headers, remaining)?,
+        maybe_parse_header(headers, reset)?,
+    ) else {
+        return Ok(None);
+    };
+
+    let reset_at = started_at
+        + Duration::try_seconds(reset)
+            .ok_or_else(|| ClientError::decode(format!("Invalid reset period: {reset}")))?;
+
+    Ok(Some(Quota {
+        limit,
+        remaining,
+        reset_at,
+    }))
+}
+
+fn maybe_parse_header(headers: &HeaderMap, header: &str) -> Result<Option<i64>, ClientError> {
+    let Some(limit) = headers.get(header) else {
+        trace!(header=%header, "rate limit header missing");
+        return Ok(None);
+    };
+    let s = limit
+        .to_str()
+        .map_err(|err| ClientError::decode(format!("{header}: {err}")))?;
+    let limit = s
+        .parse()
+        .map_err(|err| ClientError::decode(format!("{header}: {err}")))?;
+    Ok(Some(limit))
+}
+
+impl fmt::Display for QuotaScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaScope::Endpoint(class) => write!(f, "{class} endpoint"),
+            QuotaScope::Account(account_id, class) => write!(f, "account {account_id} {class}"),
+        }
+    }
+}
+
+impl fmt::Display for EndpointClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndpointClass::Token => f.write_str("token"),
+            EndpointClass::Institutions => f.write_str("institutions"),
+            EndpointClass::Agreements => f.write_str("agreements"),
+            EndpointClass::Requisitions => f.write_str("requisitions"),
+            EndpointClass::Account => f.write_str("account"),
+            EndpointClass::AccountDetails => f.write_str("details"),
+            EndpointClass::AccountBalances => f.write_str("balances"),
+            EndpointClass::AccountTransactions => f.write_str("transactions"),
+            EndpointClass::Other => f.write_str("other"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    const ACCOUNT_ID: &str = "7e944232-bda9-40bc-b784-660c7ab5fe78";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig::default())
+    }
+
+    #[test]
+    fn classifies_paths() {
+        let account_id: Uuid = ACCOUNT_ID.parse().unwrap();
+        assert_eq!(
+            EndpointClass::classify("/api/v2/token/refresh/"),
+            (EndpointClass::Token, None)
+        );
+        assert_eq!(
+            EndpointClass::classify("/api/v2/institutions/?country=gb"),
+            (EndpointClass::Institutions, None)
+        );
+        assert_eq!(
+            EndpointClass::classify(&format!("/api/v2/accounts/{ACCOUNT_ID}/")),
+            (EndpointClass::Account, Some(account_id))
+        );
+        assert_eq!(
+            EndpointClass::classify(&format!(
+                "/api/v2/accounts/{ACCOUNT_ID}/transactions/?date_from=2024-01-01"
+            )),
+            (EndpointClass::AccountTransactions, Some(account_id))
+        );
+        assert_eq!(
+            EndpointClass::classify("/proxy/api/v2/accounts/not-a-uuid/balances/"),
+            (EndpointClass::AccountBalances, None)
+        );
+        assert_eq!(
+            EndpointClass::classify("/elsewhere/"),
+            (EndpointClass::Other, None)
+        );
+    }
+
+    #[test]
+    fn parses_quota_headers() {
+        let started_at = Utc::now();
+        let quota = parse_quota(
+            &headers(&[
+                (HTTP_X_RATELIMIT_LIMIT, "100"),
+                (HTTP_X_RATELIMIT_REMAINING, "42"),
+                (HTTP_X_RATELIMIT_RESET, "60"),
+            ]),
+            started_at,
+            [
+                HTTP_X_RATELIMIT_LIMIT,
+                HTTP_X_RATELIMIT_REMAINING,
+                HTTP_X_RATELIMIT_RESET,
+            ],
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(quota.limit, 100);
+        assert_eq!(quota.remaining, 42);
+        assert_eq!(quota.reset_at, started_at + Duration::seconds(60));
+    }
+
+    #[test]
+    fn missing_quota_headers_are_not_an_error() {
+        let quota = parse_quota(
+            &headers(&[(HTTP_X_RATELIMIT_LIMIT, "100")]),
+            Utc::now(),
+            [
+                HTTP_X_RATELIMIT_LIMIT,
+                HTTP_X_RATELIMIT_REMAINING,
+                HTTP_X_RATELIMIT_RESET,
+            ],
+        )
+        .unwrap();
+        assert!(quota.is_none());
+    }
+
+    #[test]
+    fn malformed_quota_headers_are_decode_errors() {
+        let err = parse_quota(
+            &headers(&[
+                (HTTP_X_RATELIMIT_LIMIT, "lots"),
+                (HTTP_X_RATELIMIT_REMAINING, "1"),
+                (HTTP_X_RATELIMIT_RESET, "1"),
+            ]),
+            Utc::now(),
+            [
+                HTTP_X_RATELIMIT_LIMIT,
+                HTTP_X_RATELIMIT_REMAINING,
+                HTTP_X_RATELIMIT_RESET,
+            ],
+        )
+        .unwrap_err();
+        assert!(matches!(err, ClientError::Decode(_)));
+    }
+
+    #[test]
+    fn records_endpoint_and_account_quotas() {
+        let account_id: Uuid = ACCOUNT_ID.parse().unwrap();
+        let path = format!("/api/v2/accounts/{ACCOUNT_ID}/transactions/");
+        let limiter = limiter();
+        limiter
+            .record(
+                &path,
+                &headers(&[
+                    (HTTP_X_RATELIMIT_LIMIT, "100"),
+                    (HTTP_X_RATELIMIT_REMAINING, "99"),
+                    (HTTP_X_RATELIMIT_RESET, "60"),
+                    (HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_LIMIT, "4"),
+                    (HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING, "0"),
+                    (HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET, "86400"),
+                ]),
+                Utc::now(),
+            )
+            .unwrap();
+
+        let quota = limiter
+            .account_quota(account_id, EndpointClass::AccountTransactions)
+            .unwrap();
+        assert_eq!((quota.limit, quota.remaining), (4, 0));
+        assert_eq!(
+            limiter.exhausted(&path).map(|(scope, _)| scope),
+            Some(QuotaScope::Account(
+                account_id,
+                EndpointClass::AccountTransactions
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_exhausted_account_quota() {
+        let path = format!("/api/v2/accounts/{ACCOUNT_ID}/balances/");
+        let limiter = limiter();
+        limiter
+            .record(
+                &path,
+                &headers(&[
+                    (HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_LIMIT, "4"),
+                    (HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_REMAINING, "0"),
+                    (HTTP_X_RATELIMIT_ACCOUNT_SUCCESS_RESET, "86400"),
+                ]),
+                Utc::now(),
+            )
+            .unwrap();
+
+        let err = limiter.acquire(&path).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ClientError::RateLimited {
+                scope: Some(QuotaScope::Account(..)),
+                until: Some(_),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_endpoint_quota_resetting_after_max_wait() {
+        let path = "/api/v2/institutions/";
+        let limiter = limiter();
+        limiter
+            .record(
+                path,
+                &headers(&[
+                    (HTTP_X_RATELIMIT_LIMIT, "10"),
+                    (HTTP_X_RATELIMIT_REMAINING, "0"),
+                    (HTTP_X_RATELIMIT_RESET, "3600"),
+                ]),
+                Utc::now(),
+            )
+            .unwrap();
+
+        let err = limiter.acquire(path).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ClientError::RateLimited {
+                scope: Some(QuotaScope::Endpoint(EndpointClass::Institutions)),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn acquire_counts_down_remaining() {
+        let path = "/api/v2/requisitions/";
+        let limiter = limiter();
+        limiter
+            .record(
+                path,
+                &headers(&[
+                    (HTTP_X_RATELIMIT_LIMIT, "10"),
+                    (HTTP_X_RATELIMIT_REMAINING, "1"),
+                    (HTTP_X_RATELIMIT_RESET, "3600"),
+                ]),
+                Utc::now(),
+            )
+            .unwrap();
+
+        limiter.acquire(path).await.unwrap();
+        assert!(limiter.acquire(path).await.is_err());
+    }
+}
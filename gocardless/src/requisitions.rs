@@ -0,0 +1,224 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use clap::{Parser, Subcommand};
+use color_eyre::{eyre::eyre, Result};
+use serde::Deserialize;
+use tracing::{debug, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthArgs,
+    client::BankDataClient,
+    config::{ConfigArg, ProviderState, ScraperConfig},
+    connect::Requisition,
+    table::write_table,
+};
+
+const PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Parser)]
+pub struct Cmd {
+    #[clap(flatten)]
+    auth: AuthArgs,
+    #[clap(flatten)]
+    config: ConfigArg,
+    #[clap(subcommand)]
+    command: Subcmd,
+}
+
+#[derive(Debug, Subcommand)]
+enum Subcmd {
+    /// List all requisitions, marking those referenced by a provider
+    List,
+    /// Show a single requisition
+    Show {
+        #[clap(help = "Requisition id or provider name")]
+        requisition: String,
+    },
+    /// Delete a requisition, revoking access to its accounts
+    Delete {
+        #[clap(help = "Requisition id or provider name")]
+        requisition: String,
+        #[clap(
+            long,
+            help = "Also remove the local state of any provider using the requisition"
+        )]
+        remove_state: bool,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct Page<T> {
+    count: usize,
+    results: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteResponse {
+    #[serde(default)]
+    summary: String,
+}
+
+impl Cmd {
+    #[instrument("requisitions", skip_all)]
+    pub(crate) async fn run(&self) -> Result<()> {
+        let config = self.config.load().await?;
+        let client = self.auth.client(&config).await?;
+
+        match &self.command {
+            Subcmd::List => list(&client, &config).await?,
+            Subcmd::Show { requisition } => {
+                let id = resolve(&config, requisition).await?;
+                let requisition = fetch_requisition(&client, id).await?;
+                let mut out = io::stdout().lock();
+                serde_json::to_writer_pretty(&mut out, &requisition)?;
+                writeln!(out)?;
+            }
+            Subcmd::Delete {
+                requisition,
+                remove_state,
+            } => {
+                let id = resolve(&config, requisition).await?;
+                delete(&client, &config, id, *remove_state).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[instrument(skip_all)]
+async fn list(client: &BankDataClient, config: &ScraperConfig) -> Result<()> {
+    let owners = provider_requisitions(config).await;
+
+    let mut requisitions = Vec::new();
+    loop {
+        let page = client
+            .get::<Page<Requisition>>(&format!(
+                "/api/v2/requisitions/?limit={}&offset={}",
+                PAGE_SIZE,
+                requisitions.len()
+            ))
+            .await?;
+        let done = page.results.is_empty();
+        requisitions.extend(page.results);
+        if done || requisitions.len() >= page.count {
+            break;
+        }
+    }
+
+    debug!(count = requisitions.len(), "Got requisitions");
+
+    let rows = requisitions
+        .iter()
+        .map(|r| {
+            [
+                r.id.to_string(),
+                r.status.to_string(),
+                r.institution_id.clone(),
+                r.created.map(|c| c.to_rfc3339()).unwrap_or_default(),
+                r.accounts.len().to_string(),
+                owners.get(&r.id).cloned().unwrap_or_default(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    write_table(
+        &mut io::stdout().lock(),
+        [
+            "ID",
+            "STATUS",
+            "INSTITUTION",
+            "CREATED",
+            "ACCOUNTS",
+            "PROVIDER",
+        ],
+        &rows,
+    )
+}
+
+#[instrument(skip(client, config))]
+async fn delete(
+    client: &BankDataClient,
+    config: &ScraperConfig,
+    id: Uuid,
+    remove_state: bool,
+) -> Result<()> {
+    delete_requisition(client, id).await?;
+
+    // Any provider still pointing at the requisition would only fail to sync.
+    for (name, provider_config) in config.provider.iter() {
+        let uses_requisition =
+            |state: Result<ProviderState>| state.is_ok_and(|state| state.requisition_id == id);
+        if !uses_requisition(provider_config.load_state().await) {
+            continue;
+        }
+        if !remove_state {
+            warn!(
+                provider=%name,
+                path=?provider_config.state,
+                "Provider still uses the deleted requisition; run `connect` again, or pass --remove-state"
+            );
+            continue;
+        }
+
+        // Check again under the lock, in case a `connect` replaced it.
+        let _lock = provider_config.lock(false).await?;
+        if uses_requisition(provider_config.load_state().await) {
+            info!(provider=%name, path=?provider_config.state, "Removing provider state");
+            tokio::fs::remove_file(&provider_config.state).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[instrument(skip(client))]
+pub(crate) async fn delete_requisition(client: &BankDataClient, id: Uuid) -> Result<()> {
+    let resp = client
+        .delete::<DeleteResponse>(&format!("/api/v2/requisitions/{}/", id))
+        .await?;
+    info!(summary=%resp.summary, "Deleted requisition");
+    Ok(())
+}
+
+pub(crate) async fn fetch_requisition(client: &BankDataClient, id: Uuid) -> Result<Requisition> {
+    let requisition = client
+        .get::<Requisition>(&format!("/api/v2/requisitions/{}/", id))
+        .await?;
+    Ok(requisition)
+}
+
+// Accepts either a requisition id, or the name of a provider whose state
+// refers to one.
+async fn resolve(config: &ScraperConfig, requisition: &str) -> Result<Uuid> {
+    if let Ok(id) = requisition.parse::<Uuid>() {
+        return Ok(id);
+    }
+
+    let Some(provider_config) = config.provider.get(requisition) else {
+        return Err(eyre!(
+            "Neither a requisition id nor a known provider: {}",
+            requisition
+        ));
+    };
+
+    let state = provider_config.load_state().await?;
+    Ok(state.requisition_id)
+}
+
+async fn provider_requisitions(config: &ScraperConfig) -> HashMap<Uuid, String> {
+    let mut owners = HashMap::new();
+    for (name, provider_config) in config.provider.iter() {
+        match provider_config.load_state().await {
+            Ok(state) => {
+                owners.insert(state.requisition_id, name.clone());
+            }
+            Err(error) => warn!(provider=%name, %error, "Could not load provider state"),
+        }
+    }
+    owners
+}
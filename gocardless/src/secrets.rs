@@ -0,0 +1,214 @@
+use std::{env, path::PathBuf, process::Stdio};
+
+use color_eyre::{
+    eyre::{bail, eyre, Context},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{debug, instrument};
+
+const DEFAULT_SECRET_ID_VAR: &str = "GOCARDLESS_SECRET_ID";
+const DEFAULT_SECRET_KEY_VAR: &str = "GOCARDLESS_SECRET_KEY";
+const DEFAULT_CREDENTIAL_NAME: &str = "gocardless-secrets";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Secrets {
+    secret_id: String,
+    secret_key: String,
+}
+
+/// Where to find the API secrets. `file`, `command` and `systemd` all yield
+/// the same JSON document as the secrets file; `env` and `commands` yield
+/// each secret on its own.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "source", rename_all = "lowercase")]
+pub(crate) enum SecretSource {
+    File {
+        path: PathBuf,
+    },
+    Env {
+        secret_id_var: Option<String>,
+        secret_key_var: Option<String>,
+    },
+    // Must print the JSON secrets document, eg:
+    // `command = ["pass", "show", "gocardless.json"]`.
+    Command {
+        command: Vec<String>,
+    },
+    // Each prints just the one secret, eg:
+    // `secret_id_command = ["pass", "show", "gocardless/secret-id"]`.
+    Commands {
+        secret_id_command: Vec<String>,
+        secret_key_command: Vec<String>,
+    },
+    // Read from `$CREDENTIALS_DIRECTORY`, as set up by systemd's
+    // `LoadCredential=` and friends.
+    Systemd {
+        name: Option<String>,
+    },
+}
+
+impl SecretSource {
+    #[instrument(skip_all)]
+    pub(crate) async fn load(&self) -> Result<Secrets> {
+        let secrets = match self {
+            SecretSource::File { path } => {
+                let buf = tokio::fs::read(path)
+                    .await
+                    .wrap_err_with(|| format!("Read secrets file: {path:?}"))?;
+                serde_json::from_slice(&buf)
+                    .wrap_err_with(|| format!("Parse secrets file: {path:?}"))?
+            }
+            SecretSource::Env {
+                secret_id_var,
+                secret_key_var,
+            } => Secrets {
+                secret_id: env_var(secret_id_var.as_deref().unwrap_or(DEFAULT_SECRET_ID_VAR))?,
+                secret_key: env_var(secret_key_var.as_deref().unwrap_or(DEFAULT_SECRET_KEY_VAR))?,
+            },
+            SecretSource::Command { command } => {
+                let stdout = run_command(command).await?;
+                serde_json::from_slice(&stdout).wrap_err_with(|| {
+                    format!(
+                        "Secrets command {command:?} must print a JSON object with \
+                         secret_id and secret_key; use `commands` for plain values"
+                    )
+                })?
+            }
+            SecretSource::Commands {
+                secret_id_command,
+                secret_key_command,
+            } => Secrets {
+                secret_id: plain_secret(secret_id_command).await?,
+                secret_key: plain_secret(secret_key_command).await?,
+            },
+            SecretSource::Systemd { name } => {
+                let Some(dir) = env::var_os("CREDENTIALS_DIRECTORY") else {
+                    bail!("CREDENTIALS_DIRECTORY is not set; not running under systemd?");
+                };
+                let path =
+                    PathBuf::from(dir).join(name.as_deref().unwrap_or(DEFAULT_CREDENTIAL_NAME));
+                let buf = tokio::fs::read(&path)
+                    .await
+                    .wrap_err_with(|| format!("Read credential: {path:?}"))?;
+                serde_json::from_slice(&buf)
+                    .wrap_err_with(|| format!("Parse credential: {path:?}"))?
+            }
+        };
+
+        debug!(source = self.name(), "Loaded secrets");
+
+        Ok(secrets)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SecretSource::File { .. } => "file",
+            SecretSource::Env { .. } => "env",
+            SecretSource::Command { .. } => "command",
+            SecretSource::Commands { .. } => "commands",
+            SecretSource::Systemd { .. } => "systemd",
+        }
+    }
+}
+
+fn env_var(name: &str) -> Result<String> {
+    env::var(name).wrap_err_with(|| format!("Read secret from environment: {name}"))
+}
+
+// Only the first line counts, as with `pass show`, which may be followed by
+// other metadata.
+async fn plain_secret(command: &[String]) -> Result<String> {
+    let stdout = run_command(command).await?;
+    let stdout = String::from_utf8(stdout)
+        .wrap_err_with(|| format!("Secrets command {command:?} printed invalid UTF-8"))?;
+    let secret = stdout.lines().next().unwrap_or_default().trim();
+    if secret.is_empty() {
+        bail!("Secrets command {command:?} printed nothing");
+    }
+    Ok(secret.to_owned())
+}
+
+async fn run_command(command: &[String]) -> Result<Vec<u8>> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("Secrets command is empty");
+    };
+
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .await
+        .wrap_err_with(|| format!("Run secrets command: {program:?}"))?;
+
+    if !output.status.success() {
+        return Err(eyre!(
+            "Secrets command {:?} failed: {}",
+            program,
+            output.status
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn command_prints_json() {
+        let secrets = SecretSource::Command {
+            command: command(&["echo", r#"{"secret_id": "id", "secret_key": "key"}"#]),
+        }
+        .load()
+        .await
+        .unwrap();
+
+        assert_eq!(secrets.secret_id, "id");
+        assert_eq!(secrets.secret_key, "key");
+    }
+
+    #[tokio::test]
+    async fn command_explains_plain_output() {
+        let err = SecretSource::Command {
+            command: command(&["echo", "hunter2"]),
+        }
+        .load()
+        .await
+        .unwrap_err();
+
+        assert!(format!("{err}").contains("must print a JSON object"));
+    }
+
+    #[tokio::test]
+    async fn commands_print_plain_values() {
+        let secrets = SecretSource::Commands {
+            secret_id_command: command(&["echo", "id"]),
+            secret_key_command: command(&["printf", "key\nlogin: me\n"]),
+        }
+        .load()
+        .await
+        .unwrap();
+
+        assert_eq!(secrets.secret_id, "id");
+        assert_eq!(secrets.secret_key, "key");
+    }
+
+    #[tokio::test]
+    async fn commands_reject_empty_output() {
+        assert!(SecretSource::Commands {
+            secret_id_command: command(&["true"]),
+            secret_key_command: command(&["echo", "key"]),
+        }
+        .load()
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use std::io;
+
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use color_eyre::{eyre::eyre, Result};
+use tracing::{instrument, warn};
+
+use crate::{
+    accounts::{Account, AccountStatus},
+    agreements::record_consent,
+    auth::AuthArgs,
+    client::BankDataClient,
+    config::{ConfigArg, ProviderConfig},
+    requisitions::fetch_requisition,
+    table::write_table,
+};
+
+#[derive(Debug, Parser)]
+pub struct Cmd {
+    #[clap(flatten)]
+    auth: AuthArgs,
+    #[clap(flatten)]
+    config: ConfigArg,
+    #[clap(
+        long = "warn-days",
+        default_value_t = 7,
+        help = "Flag consents expiring within this many days"
+    )]
+    warn_days: i64,
+}
+
+#[derive(Debug, Default)]
+struct ProviderStatus {
+    requisition: String,
+    accounts: Vec<AccountStatus>,
+    consent_expires: Option<DateTime<Utc>>,
+    last_synced: Option<DateTime<Utc>>,
+    problem: Option<String>,
+}
+
+impl Cmd {
+    #[instrument("status", skip_all)]
+    pub(crate) async fn run(&self) -> Result<()> {
+        let config = self.config.load().await?;
+        let client = self.auth.client(&config).await?;
+
+        let now = Utc::now();
+        let mut providers = config.provider.iter().collect::<Vec<_>>();
+        providers.sort_by_key(|(name, _)| *name);
+
+        let mut rows = Vec::new();
+        let mut needs_attention = 0;
+        for (name, provider_config) in providers {
+            let status = match self.provider_status(&client, provider_config).await {
+                Ok(status) => status,
+                Err(error) => {
+                    warn!(provider=%name, ?error, "Could not determine provider status");
+                    ProviderStatus {
+                        problem: Some(format!("error: {error}")),
+                        ..Default::default()
+                    }
+                }
+            };
+
+            let days_left = status
+                .consent_expires
+                .map(|expires| (expires - now).num_days());
+            let problem = status.problem.clone().or_else(|| match days_left {
+                Some(days) if days <= self.warn_days => Some("consent expiring".to_owned()),
+                _ => None,
+            });
+            if problem.is_some() {
+                needs_attention += 1;
+            }
+
+            rows.push([
+                name.clone(),
+                status.requisition,
+                status
+                    .accounts
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+                status
+                    .consent_expires
+                    .map(|e| e.date_naive().to_string())
+                    .unwrap_or_default(),
+                days_left.map(|d| d.to_string()).unwrap_or_default(),
+                status
+                    .last_synced
+                    .map(|s| s.to_rfc3339())
+                    .unwrap_or_default(),
+                problem.unwrap_or_default(),
+            ]);
+        }
+
+        write_table(
+            &mut io::stdout().lock(),
+            [
+                "PROVIDER",
+                "REQUISITION",
+                "ACCOUNTS",
+                "EXPIRES",
+                "DAYS LEFT",
+                "LAST SYNC",
+                "ATTENTION",
+            ],
+            &rows,
+        )?;
+
+        if needs_attention > 0 {
+            return Err(eyre!(
+                "{} provider(s) need re-authentication soon",
+                needs_attention
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn provider_status(
+        &self,
+        client: &BankDataClient,
+        provider_config: &ProviderConfig,
+    ) -> Result<ProviderStatus> {
+        if !provider_config.state.exists() {
+            return Ok(ProviderStatus {
+                problem: Some("not connected".to_owned()),
+                ..Default::default()
+            });
+        }
+
+        let mut state = provider_config.load_state().await?;
+        let requisition = fetch_requisition(client, state.requisition_id).await?;
+
+        if state.agreement_id.is_none() {
+            state.agreement_id = requisition.agreement;
+        }
+        if state.consent_expires.is_none() {
+            record_consent(client, &mut state).await?;
+        }
+
+        let mut accounts = Vec::new();
+        for account_id in requisition.accounts.iter() {
+            let account = client
+                .get::<Account>(&format!("/api/v2/accounts/{}/", account_id))
+                .await?;
+            accounts.push(account.status);
+        }
+
+        let problem = if !requisition.is_linked() {
+            Some(format!("requisition {}", requisition.status))
+        } else if accounts.iter().any(|s| *s != AccountStatus::Ready) {
+            Some("account not ready".to_owned())
+        } else {
+            None
+        };
+
+        Ok(ProviderStatus {
+            requisition: requisition.status.to_string(),
+            accounts,
+            consent_expires: state.consent_expires,
+            last_synced: state.last_synced,
+            problem,
+        })
+    }
+}
@@ -0,0 +1,221 @@
+use std::{io::Write, process::ExitCode};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use color_eyre::Result;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{ratelimit::EndpointClass, table::write_table};
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SyncSummary {
+    pub(crate) started_at: DateTime<Utc>,
+    pub(crate) finished_at: DateTime<Utc>,
+    pub(crate) providers: Vec<ProviderSummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ProviderSummary {
+    pub(crate) provider: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    // The transaction window actually requested, after clamping.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) history_days: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) date_from: Option<NaiveDate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) date_to: Option<NaiveDate>,
+    pub(crate) accounts: Vec<AccountSummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct AccountSummary {
+    pub(crate) account_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) directory: Option<String>,
+    pub(crate) result: AccountResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    // Rows in the month files we rewrote, after merging with what was there.
+    pub(crate) transactions_written: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) skipped: Vec<EndpointClass>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AccountResult {
+    Succeeded,
+    // Nothing went wrong, but the transactions were not fetched for lack of
+    // quota.
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SyncOutcome {
+    Succeeded,
+    Partial,
+    Failed,
+}
+
+impl SyncSummary {
+    pub(crate) fn outcome(&self) -> SyncOutcome {
+        let accounts = self.providers.iter().flat_map(|p| p.accounts.iter());
+        let count = |result| accounts.clone().filter(|a| a.result == result).count();
+        let succeeded = count(AccountResult::Succeeded);
+        let skipped = count(AccountResult::Skipped);
+        let failed = count(AccountResult::Failed)
+            + self.providers.iter().filter(|p| p.error.is_some()).count();
+
+        match (succeeded, skipped, failed) {
+            (_, 0, 0) => SyncOutcome::Succeeded,
+            (0, 0, _) => SyncOutcome::Failed,
+            _ => SyncOutcome::Partial,
+        }
+    }
+
+    pub(crate) fn write_table(&self, out: &mut impl Write) -> Result<()> {
+        let mut rows = Vec::new();
+        for provider in self.providers.iter() {
+            let date_from = provider
+                .date_from
+                .map(|d| d.to_string())
+                .unwrap_or_default();
+            if let Some(error) = &provider.error {
+                rows.push([
+                    provider.provider.clone(),
+                    String::new(),
+                    date_from.clone(),
+                    "failed".to_owned(),
+                    String::new(),
+                    String::new(),
+                    error.clone(),
+                ]);
+            }
+            for account in provider.accounts.iter() {
+                rows.push([
+                    provider.provider.clone(),
+                    account
+                        .directory
+                        .clone()
+                        .unwrap_or_else(|| account.account_id.to_string()),
+                    date_from.clone(),
+                    account.result.as_str().to_owned(),
+                    account.transactions_written.to_string(),
+                    account
+                        .skipped
+                        .iter()
+                        .map(|s| s.to_string())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    account.error.clone().unwrap_or_default(),
+                ]);
+            }
+        }
+
+        write_table(
+            out,
+            [
+                "PROVIDER",
+                "ACCOUNT",
+                "FROM",
+                "RESULT",
+                "TRANSACTIONS",
+                "SKIPPED",
+                "ERROR",
+            ],
+            &rows,
+        )
+    }
+}
+
+impl ProviderSummary {
+    pub(crate) fn new(provider: &str) -> Self {
+        ProviderSummary {
+            provider: provider.to_owned(),
+            error: None,
+            history_days: None,
+            date_from: None,
+            date_to: None,
+            accounts: Vec::new(),
+        }
+    }
+}
+
+impl AccountSummary {
+    pub(crate) fn new(account_id: Uuid) -> Self {
+        AccountSummary {
+            account_id,
+            directory: None,
+            result: AccountResult::Failed,
+            error: None,
+            transactions_written: 0,
+            skipped: Vec::new(),
+        }
+    }
+}
+
+impl AccountResult {
+    fn as_str(self) -> &'static str {
+        match self {
+            AccountResult::Succeeded => "ok",
+            AccountResult::Skipped => "skipped",
+            AccountResult::Failed => "failed",
+        }
+    }
+}
+
+impl SyncOutcome {
+    // Distinguishes a partial failure from a total one, for cron and friends.
+    pub(crate) fn exit_code(self) -> ExitCode {
+        match self {
+            SyncOutcome::Succeeded => ExitCode::SUCCESS,
+            SyncOutcome::Failed => ExitCode::from(1),
+            SyncOutcome::Partial => ExitCode::from(2),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(results: &[AccountResult]) -> SyncSummary {
+        let mut provider = ProviderSummary::new("mock");
+        provider.accounts = results
+            .iter()
+            .map(|&result| AccountSummary {
+                result,
+                ..AccountSummary::new(Uuid::nil())
+            })
+            .collect();
+        SyncSummary {
+            started_at: Utc::now(),
+            finished_at: Utc::now(),
+            providers: vec![provider],
+        }
+    }
+
+    #[test]
+    fn outcome_reflects_account_results() {
+        use AccountResult::*;
+
+        assert_eq!(summary(&[Succeeded]).outcome(), SyncOutcome::Succeeded);
+        assert_eq!(
+            summary(&[Succeeded, Failed]).outcome(),
+            SyncOutcome::Partial
+        );
+        assert_eq!(summary(&[Failed]).outcome(), SyncOutcome::Failed);
+        assert_eq!(summary(&[Skipped]).outcome(), SyncOutcome::Partial);
+        assert_eq!(summary(&[Skipped, Failed]).outcome(), SyncOutcome::Partial);
+    }
+
+    #[test]
+    fn provider_failure_counts_as_failed() {
+        let mut summary = summary(&[]);
+        summary.providers[0].error = Some("Requisition not linked".to_owned());
+        assert_eq!(summary.outcome(), SyncOutcome::Failed);
+    }
+}
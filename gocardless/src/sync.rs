@@ -1,4 +1,11 @@
-use std::{cmp::Ordering, collections::HashMap};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
 use chrono::{DateTime, Datelike, Days, Local, Months, NaiveDate, Utc};
 use clap::Parser;
@@ -6,18 +13,27 @@ use color_eyre::{
     eyre::{bail, eyre},
     Result,
 };
+use futures::future::join_all;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, instrument};
+use tokio::sync::{Mutex, Semaphore};
+use tracing::{debug, error, instrument, warn};
 use uuid::Uuid;
 
 use crate::{
-    accounts::{Account, AccountStatus, Balances},
+    accounts::{Account, AccountDetailsResponse, AccountStatus, Balances, CachedAccountDetails},
+    agreements::{apply_consent, fetch_agreement, AccessScope, EndUserAgreement},
     auth::AuthArgs,
     client::BankDataClient,
     config::{ConfigArg, ProviderConfig, ScraperConfig},
     connect::Requisition,
-    files::write_json_lines,
+    files::{read_json, read_json_lines, write_json_atomically, write_json_lines},
+    institutions::fetch_institution,
+    ledger::Ledger,
+    ratelimit::EndpointClass,
+    summary::{AccountResult, AccountSummary, ProviderSummary, SyncSummary},
+    table::OutputFormat,
     transactions::{Transaction, Transactions, TransactionsQuery},
+    transitions::{record_transitions, Observations},
 };
 
 #[derive(Debug, Parser)]
@@ -26,13 +42,42 @@ pub struct Cmd {
     auth: AuthArgs,
     #[clap(flatten)]
     config: ConfigArg,
-    #[clap(short = 'p', long = "provider", help = "Provider name")]
-    provider: String,
+    #[clap(
+        short = 'p',
+        long = "provider",
+        help = "Provider name; may be repeated",
+        required_unless_present = "all"
+    )]
+    provider: Vec<String>,
+    #[clap(
+        long = "all",
+        help = "Sync every configured provider",
+        conflicts_with = "provider"
+    )]
+    all: bool,
+    #[clap(
+        short = 'j',
+        long = "concurrent-tasks",
+        help = "Maximum number of providers being set up or accounts being synced at once"
+    )]
+    concurrency: Option<usize>,
+    #[clap(
+        long = "wait",
+        help = "Wait for other runs using the same provider, rather than failing"
+    )]
+    wait: bool,
+    #[clap(long = "format", value_enum, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+    #[clap(
+        long = "summary",
+        help = "Also write the run summary as JSON to this file"
+    )]
+    summary: Option<PathBuf>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "status")]
-enum TransactionWithStatus {
+pub(crate) enum TransactionWithStatus {
     #[serde(rename = "pending")]
     Pending(Transaction),
     #[serde(rename = "booked")]
@@ -63,21 +108,165 @@ impl TransactionWithStatus {
             }
         }
     }
+
+    fn transaction(&self) -> &Transaction {
+        match self {
+            TransactionWithStatus::Pending(transaction) => transaction,
+            TransactionWithStatus::Booked(transaction) => transaction,
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        matches!(self, TransactionWithStatus::Pending(_))
+    }
+
+    // Either id is enough to match, as some banks only add `transactionId`
+    // once a transaction is booked. Banks do not always supply either, so we
+    // fall back to hashing the transaction content.
+    fn keys(&self) -> Result<Vec<TransactionKey>> {
+        let keys = self
+            .transaction_id()
+            .map(|id| TransactionKey::Id(id.to_owned()))
+            .into_iter()
+            .chain(
+                self.internal_transaction_id()
+                    .map(|id| TransactionKey::InternalId(id.to_owned())),
+            )
+            .collect::<Vec<_>>();
+        if !keys.is_empty() {
+            return Ok(keys);
+        }
+
+        let content = serde_json::to_string(self.transaction())?;
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        Ok(vec![TransactionKey::Content(hasher.finish())])
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DateRange {
+    start: NaiveDate,
+    end: NaiveDate,
+}
+
+// What to fetch for each account on a requisition.
+#[derive(Debug, Clone, Copy)]
+struct FetchPlan<'a> {
+    dates: DateRange,
+    // `None` when we do not know which agreement the requisition was made
+    // under, in which case we try everything.
+    access_scope: Option<&'a [AccessScope]>,
+}
+
+impl FetchPlan<'_> {
+    // Endpoints the agreement does not cover are rejected, and count
+    // against the account's quota for nothing.
+    fn allows(&self, scope: AccessScope) -> bool {
+        self.access_scope
+            .is_none_or(|access_scope| access_scope.contains(&scope))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum TransactionKey {
+    Id(String),
+    InternalId(String),
+    Content(u64),
 }
 
 impl Cmd {
-    #[instrument("sync", skip_all, fields(provider = %self.provider))]
-    pub(crate) async fn run(&self) -> Result<()> {
+    #[instrument("sync", skip_all)]
+    pub(crate) async fn run(&self) -> Result<ExitCode> {
         let config: ScraperConfig = self.config.load().await?;
-        let token = self.auth.load_token().await?;
 
-        let Some(provider_config) = config.provider.get(&self.provider) else {
-            return Err(eyre!("Unrecognised provider: {}", self.provider));
+        let providers = self.selected_providers(&config)?;
+
+        // One client (and so one token, and one view of the rate limits)
+        // shared between every provider.
+        let client = self.auth.client(&config).await?;
+
+        let limit = Semaphore::new(self.concurrency.unwrap_or(1).max(1));
+
+        let started_at = Utc::now();
+        let (client, limit) = (&client, &limit);
+        let providers = join_all(providers.iter().map(|(name, provider_config)| async move {
+            let mut summary = ProviderSummary::new(name);
+            if let Err(error) = self
+                .sync_provider(name, provider_config, client, limit, &mut summary)
+                .await
+            {
+                error!(provider=%name, ?error, "Sync failed");
+                summary.error = Some(format!("{error:#}"));
+            }
+            summary
+        }))
+        .await;
+
+        let summary = SyncSummary {
+            started_at,
+            finished_at: Utc::now(),
+            providers,
         };
+        let outcome = summary.outcome();
+
+        let mut out = io::stdout().lock();
+        match self.format {
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut out, &summary)?;
+                writeln!(out)?;
+            }
+            OutputFormat::Table => summary.write_table(&mut out)?,
+        }
+
+        if let Some(path) = &self.summary {
+            write_json_atomically(path, summary).await?;
+        }
+
+        Ok(outcome.exit_code())
+    }
+
+    fn selected_providers<'a>(
+        &self,
+        config: &'a ScraperConfig,
+    ) -> Result<Vec<(&'a str, &'a ProviderConfig)>> {
+        if self.all {
+            let mut providers = config
+                .provider
+                .iter()
+                .map(|(name, provider_config)| (name.as_str(), provider_config))
+                .collect::<Vec<_>>();
+            providers.sort_by_key(|(name, _)| *name);
+            return Ok(providers);
+        }
+
+        // A provider named twice would otherwise wait on its own lock.
+        let mut seen = HashSet::new();
+        self.provider
+            .iter()
+            .filter(|name| seen.insert(name.as_str()))
+            .map(|name| match config.provider.get_key_value(name) {
+                Some((name, provider_config)) => Ok((name.as_str(), provider_config)),
+                None => Err(eyre!("Unrecognised provider: {}", name)),
+            })
+            .collect()
+    }
 
-        let client = BankDataClient::new(token, &config.retries);
+    #[instrument(skip_all, fields(provider = %name))]
+    async fn sync_provider(
+        &self,
+        name: &str,
+        provider_config: &ProviderConfig,
+        client: &BankDataClient,
+        limit: &Semaphore,
+        summary: &mut ProviderSummary,
+    ) -> Result<()> {
+        let _lock = provider_config.lock(self.wait).await?;
+        let mut state = provider_config.load_state().await?;
 
-        let state = provider_config.load_state().await?;
+        // Setting up counts against the same limit as syncing an account, but
+        // the permit is released before the accounts queue for their own.
+        let permit = limit.acquire().await?;
 
         let requisition = client
             .get::<Requisition>(&format!("/api/v2/requisitions/{}/", state.requisition_id))
@@ -89,18 +278,85 @@ impl Cmd {
             return Err(eyre!("Requisition not linked"));
         }
 
+        if state.agreement_id.is_none() {
+            state.agreement_id = requisition.agreement;
+        }
+        let agreement = match state.agreement_id {
+            Some(agreement_id) => Some(fetch_agreement(client, agreement_id).await?),
+            None => None,
+        };
+
+        let history_days =
+            effective_history_days(client, provider_config, agreement.as_ref()).await?;
+        drop(permit);
+
         let end_date = Local::now().date_naive();
-        let mut start_date = end_date - provider_config.history_days();
+        let mut start_date = end_date - Days::new(history_days);
         if start_date.day() > 1 {
             start_date = start_date + Months::new(1);
             start_date = start_date - Days::new(start_date.day0().into());
         }
         debug!(%start_date, %end_date, "Scanning date range");
+        let plan = FetchPlan {
+            dates: DateRange {
+                start: start_date,
+                end: end_date,
+            },
+            access_scope: agreement
+                .as_ref()
+                .map(|agreement| agreement.access_scope.as_slice()),
+        };
+        summary.history_days = Some(history_days);
+        summary.date_from = Some(start_date);
+        summary.date_to = Some(end_date);
+
+        let ledger_path = provider_config.ledger_path();
+        let ledger = Mutex::new(Ledger::load(&ledger_path).await?);
+
+        // One account failing (eg: because it is suspended) should not
+        // stop us from fetching the others on the same requisition.
+        let (ledger, ledger_path) = (&ledger, &ledger_path);
+        summary.accounts = join_all(requisition.accounts.iter().cloned().map(|acc| async move {
+            let mut summary = AccountSummary::new(acc);
+            let res = async {
+                let _permit = limit.acquire().await?;
+                let res = self
+                    .list_account(provider_config, client, ledger, acc, plan, &mut summary)
+                    .await;
+                ledger.lock().await.store(ledger_path).await?;
+                res
+            }
+            .await;
+            match res {
+                Ok(())
+                    if summary
+                        .skipped
+                        .contains(&EndpointClass::AccountTransactions) =>
+                {
+                    summary.result = AccountResult::Skipped
+                }
+                Ok(()) => summary.result = AccountResult::Succeeded,
+                Err(error) => {
+                    error!(account_id=%acc, ?error, "Account sync failed");
+                    summary.error = Some(format!("{error:#}"));
+                }
+            }
+            summary
+        }))
+        .await;
 
-        for acc in requisition.accounts.iter().cloned() {
-            self.list_account(provider_config, &client, acc, start_date, end_date)
-                .await?;
+        if let (None, Some(agreement)) = (state.consent_expires, &agreement) {
+            apply_consent(agreement, &mut state);
         }
+        if summary
+            .accounts
+            .iter()
+            .any(|account| account.result == AccountResult::Succeeded)
+        {
+            state.last_synced = Some(Utc::now());
+        }
+        provider_config.write_state(&state).await?;
+
         Ok(())
     }
 
@@ -109,13 +365,16 @@ impl Cmd {
         &self,
         provider_config: &ProviderConfig,
         client: &BankDataClient,
+        ledger: &Mutex<Ledger>,
         account_id: Uuid,
-        start_date: NaiveDate,
-        end_date: NaiveDate,
+        plan: FetchPlan<'_>,
+        summary: &mut AccountSummary,
     ) -> Result<()> {
         let details = fetch_account(client, account_id).await?;
 
-        let account_base = provider_config.output.join(&details.iban);
+        let directory = details.directory_key(&provider_config.account_aliases);
+        let account_base = provider_config.output.join(&directory);
+        summary.directory = Some(directory);
 
         let status = details.status.clone();
 
@@ -125,61 +384,313 @@ impl Cmd {
             bail!("Account status is not ready: {status:?}")
         }
 
-        let balances = fetch_balances(client, account_id).await?;
+        // Transactions first, so that they get first claim on any budget.
+        if !plan.allows(AccessScope::Transactions) {
+            debug!("Transactions are not in the agreement's access scope; skipping");
+        } else if can_afford(
+            &*ledger.lock().await,
+            account_id,
+            EndpointClass::AccountTransactions,
+        ) {
+            let dates = plan.dates;
+            let transactions = fetch_transactions(client, account_id, dates.start, dates.end).await;
+            record_call(
+                &mut *ledger.lock().await,
+                client,
+                account_id,
+                EndpointClass::AccountTransactions,
+                &transactions,
+            );
+            summary.transactions_written =
+                write_transactions(&account_base, transactions?, dates).await?;
+        } else {
+            summary.skipped.push(EndpointClass::AccountTransactions);
+        }
+
+        if !plan.allows(AccessScope::Balances) {
+            debug!("Balances are not in the agreement's access scope; skipping");
+        } else if can_afford(
+            &*ledger.lock().await,
+            account_id,
+            EndpointClass::AccountBalances,
+        ) {
+            let balances = fetch_balances(client, account_id).await;
+            record_call(
+                &mut *ledger.lock().await,
+                client,
+                account_id,
+                EndpointClass::AccountBalances,
+                &balances,
+            );
+            write_json_lines(&account_base.join("balances.jsonl"), balances?.balances).await?;
+        } else {
+            summary.skipped.push(EndpointClass::AccountBalances);
+        }
 
-        write_json_lines(&account_base.join("balances.jsonl"), balances.balances).await?;
+        let details_path = account_base.join("account-details-cache.json");
+        let cached = read_json::<CachedAccountDetails>(&details_path).await?;
+        let stale = cached.as_ref().is_none_or(|cached| {
+            cached
+                .fetched_at
+                .checked_add_days(provider_config.details_refresh())
+                .is_none_or(|refresh_at| refresh_at <= Utc::now())
+        });
+
+        if !plan.allows(AccessScope::Details) {
+            debug!("Details are not in the agreement's access scope; skipping");
+        } else if !stale {
+            debug!("Account details are fresh; not re-fetching");
+        } else if can_afford(
+            &*ledger.lock().await,
+            account_id,
+            EndpointClass::AccountDetails,
+        ) {
+            let fetched_at = Utc::now();
+            let details = fetch_details(client, account_id).await;
+            record_call(
+                &mut *ledger.lock().await,
+                client,
+                account_id,
+                EndpointClass::AccountDetails,
+                &details,
+            );
+            let cached = CachedAccountDetails {
+                fetched_at,
+                details: details?.account,
+            };
+            write_json_atomically(&details_path, cached).await?;
+        } else {
+            summary.skipped.push(EndpointClass::AccountDetails);
+        }
 
-        let transactions = fetch_transactions(client, account_id, start_date, end_date).await?;
+        Ok(())
+    }
+}
 
-        let mut by_month = HashMap::<_, Vec<_>>::new();
+// Asking for more history than the institution keeps, or than the agreement
+// grants, gets the request rejected or quietly truncated.
+async fn effective_history_days(
+    client: &BankDataClient,
+    provider_config: &ProviderConfig,
+    agreement: Option<&EndUserAgreement>,
+) -> Result<u64> {
+    let configured = provider_config.history_days();
+    let institution = fetch_institution(client, &provider_config.institution_id).await?;
+    let agreement_max = agreement.map(|agreement| agreement.max_historical_days);
+
+    let effective = agreement_max
+        .into_iter()
+        .chain([institution.transaction_total_days, configured])
+        .min()
+        .unwrap_or(configured);
+
+    if effective < configured {
+        warn!(
+            %configured,
+            transaction_total_days = %institution.transaction_total_days,
+            max_historical_days = ?agreement_max,
+            %effective,
+            "Configured history_days exceeds what is available; reducing"
+        );
+    }
 
-        for booked in transactions.transactions.booked {
-            let date = booked.date_best_effort();
-            let start_of_month = date.map(|d| d.with_day(1).expect("valid date"));
+    Ok(effective)
+}
 
-            by_month
-                .entry(start_of_month)
-                .or_default()
-                .push(TransactionWithStatus::Booked(booked))
+fn can_afford(ledger: &Ledger, account_id: Uuid, class: EndpointClass) -> bool {
+    match ledger.exhausted_until(account_id, class, Utc::now()) {
+        Some(until) => {
+            warn!(endpoint=%class, %until, "Account quota exhausted; skipping");
+            false
         }
+        None => true,
+    }
+}
+
+fn record_call<T>(
+    ledger: &mut Ledger,
+    client: &BankDataClient,
+    account_id: Uuid,
+    class: EndpointClass,
+    result: &Result<T>,
+) {
+    ledger.record(
+        account_id,
+        class,
+        result.is_ok(),
+        client.account_quota(account_id, class),
+        Utc::now(),
+    );
+}
+
+async fn write_transactions(
+    account_base: &Path,
+    transactions: Transactions,
+    dates: DateRange,
+) -> Result<usize> {
+    let mut written = 0;
+    let mut by_month = HashMap::<_, Vec<_>>::new();
+    let mut observations = Observations::default();
+
+    // Buckets we fetched in full are revisited even when nothing came back
+    // for them, so that pendings which have since vanished are noticed.
+    // Undated transactions cannot be filtered by date, so every fetch
+    // covers them.
+    let mut month = dates.start;
+    while month <= dates.end {
+        by_month.entry(Some(month)).or_default();
+        month = month + Months::new(1);
+    }
+    by_month.entry(None).or_default();
+
+    for booked in transactions.transactions.booked {
+        let date = booked.date_best_effort();
+        let start_of_month = date.map(|d| d.with_day(1).expect("valid date"));
+
+        by_month
+            .entry(start_of_month)
+            .or_default()
+            .push(TransactionWithStatus::Booked(booked))
+    }
+
+    for pending in transactions.transactions.pending {
+        let date = pending.date_best_effort();
+        let start_of_month = date.map(|d| d.with_day(1).expect("valid date"));
 
-        for pending in transactions.transactions.pending {
-            let date = pending.date_best_effort();
-            let start_of_month = date.map(|d| d.with_day(1).expect("valid date"));
+        by_month
+            .entry(start_of_month)
+            .or_default()
+            .push(TransactionWithStatus::Pending(pending))
+    }
+
+    for (month, fetched) in by_month {
+        let fname = month
+            .map(|month| month.format("%Y-%m.jsonl").to_string())
+            .unwrap_or_else(|| "undated.json".to_owned());
+
+        let path = account_base.join(fname);
+        let existing = read_json_lines::<TransactionWithStatus>(&path).await?;
 
-            by_month
-                .entry(start_of_month)
-                .or_default()
-                .push(TransactionWithStatus::Pending(pending))
+        // If we fetched the whole bucket, then the bank's current view of
+        // what is pending supersedes whatever we saw before.
+        let covers_month = month.is_none_or(|month| month >= dates.start);
+        if covers_month {
+            observations.observe(&existing, &fetched);
         }
 
-        for (month, mut transactions) in by_month {
-            let fname = month
-                .map(|month| month.format("%Y-%m.jsonl").to_string())
-                .unwrap_or_else(|| "undated.json".to_owned());
+        if existing.is_empty() && fetched.is_empty() {
+            continue;
+        }
 
-            transactions.sort_by(|a, b| {
-                let cmp = if let (Some(left), Some(right)) =
-                    (a.timestamp_best_effort(), b.timestamp_best_effort())
-                {
-                    left.cmp(&right)
-                } else {
-                    Ordering::Equal
-                };
+        let mut transactions = merge_transactions(existing, fetched, covers_month)?;
+        sort_transactions(&mut transactions);
+        written += transactions.len();
 
-                cmp.then_with(|| a.transaction_id().cmp(&b.transaction_id()))
-                    .then_with(|| {
-                        a.internal_transaction_id()
-                            .cmp(&b.internal_transaction_id())
-                    })
-            });
+        write_json_lines(&path, transactions).await?;
+    }
+
+    record_transitions(
+        &account_base.join("transitions.jsonl"),
+        observations.transitions(),
+        Utc::now(),
+    )
+    .await?;
 
-            let path = account_base.join(fname);
-            write_json_lines(&path, transactions).await?;
+    Ok(written)
+}
+
+fn merge_transactions(
+    existing: Vec<TransactionWithStatus>,
+    fetched: Vec<TransactionWithStatus>,
+    replace_pending: bool,
+) -> Result<Vec<TransactionWithStatus>> {
+    let mut merged = Vec::<TransactionWithStatus>::new();
+    let mut index = MergeIndex::default();
+
+    for tx in existing {
+        if replace_pending && tx.is_pending() {
+            continue;
         }
+        upsert_transaction(&mut merged, &mut index, tx, true)?;
+    }
 
-        Ok(())
+    for tx in fetched {
+        upsert_transaction(&mut merged, &mut index, tx, false)?;
+    }
+
+    Ok(merged)
+}
+
+// Transactions without ids can only be told apart by their content, but
+// identical ones (eg: two coffees on the same day) are still distinct. So
+// each fetched one may only match a single entry from disk that nothing else
+// has matched yet, and never one from the same fetch.
+#[derive(Debug, Default)]
+struct MergeIndex {
+    by_id: HashMap<TransactionKey, usize>,
+    unmatched_by_content: HashMap<TransactionKey, Vec<usize>>,
+}
+
+// Replaces the entry sharing any of `tx`'s keys, or appends it. The entry is
+// then indexed under every key either of them had.
+fn upsert_transaction(
+    merged: &mut Vec<TransactionWithStatus>,
+    index: &mut MergeIndex,
+    tx: TransactionWithStatus,
+    from_disk: bool,
+) -> Result<()> {
+    let keys = tx.keys()?;
+    let found = match keys.as_slice() {
+        [TransactionKey::Content(_)] if from_disk => None,
+        [content @ TransactionKey::Content(_)] => index
+            .unmatched_by_content
+            .get_mut(content)
+            .and_then(Vec::pop),
+        ids => ids.iter().find_map(|key| index.by_id.get(key).copied()),
+    };
+    let i = match found {
+        // Never replace a booked transaction with a pending one.
+        Some(i) if tx.is_pending() && !merged[i].is_pending() => return Ok(()),
+        Some(i) => {
+            merged[i] = tx;
+            i
+        }
+        None => {
+            merged.push(tx);
+            merged.len() - 1
+        }
+    };
+    for key in keys {
+        match key {
+            TransactionKey::Content(_) if from_disk => {
+                index.unmatched_by_content.entry(key).or_default().push(i)
+            }
+            TransactionKey::Content(_) => {}
+            key => {
+                index.by_id.insert(key, i);
+            }
+        }
     }
+
+    Ok(())
+}
+
+fn sort_transactions(transactions: &mut [TransactionWithStatus]) {
+    transactions.sort_by(|a, b| {
+        let cmp = if let (Some(left), Some(right)) =
+            (a.timestamp_best_effort(), b.timestamp_best_effort())
+        {
+            left.cmp(&right)
+        } else {
+            Ordering::Equal
+        };
+
+        cmp.then_with(|| a.transaction_id().cmp(&b.transaction_id()))
+            .then_with(|| {
+                a.internal_transaction_id()
+                    .cmp(&b.internal_transaction_id())
+            })
+    });
 }
 
 #[instrument(skip_all)]
@@ -193,6 +704,17 @@ async fn fetch_account(
     Ok(details)
 }
 
+#[instrument(skip_all)]
+async fn fetch_details(
+    client: &BankDataClient,
+    account_id: Uuid,
+) -> Result<AccountDetailsResponse> {
+    let details = client
+        .get::<AccountDetailsResponse>(&format!("/api/v2/accounts/{}/details/", account_id))
+        .await?;
+    Ok(details)
+}
+
 #[instrument(skip_all)]
 async fn fetch_balances(client: &BankDataClient, account_id: Uuid) -> Result<Balances> {
     let balances = client
@@ -221,3 +743,226 @@ async fn fetch_transactions(
         .await?;
     Ok(transactions)
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tx(value: serde_json::Value) -> Transaction {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn pending(value: serde_json::Value) -> TransactionWithStatus {
+        TransactionWithStatus::Pending(tx(value))
+    }
+
+    fn booked(value: serde_json::Value) -> TransactionWithStatus {
+        TransactionWithStatus::Booked(tx(value))
+    }
+
+    fn statuses(merged: &[TransactionWithStatus]) -> Vec<(bool, Option<&str>)> {
+        merged
+            .iter()
+            .map(|tx| (tx.is_pending(), tx.transaction_id()))
+            .collect()
+    }
+
+    fn fetched(pending: Vec<Transaction>, booked: Vec<Transaction>) -> Transactions {
+        let mut transactions = Transactions::default();
+        transactions.transactions.pending = pending;
+        transactions.transactions.booked = booked;
+        transactions
+    }
+
+    async fn transitions(account_base: &Path) -> Vec<String> {
+        read_json_lines::<serde_json::Value>(&account_base.join("transitions.jsonl"))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|transition| transition["event"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn undated_pending_appears_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let dates = DateRange {
+            start: NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(),
+            end: NaiveDate::from_ymd_opt(2024, 3, 15).unwrap(),
+        };
+        let undated = tx(json!({"transactionId": "u", "note": "no date"}));
+
+        for _ in 0..2 {
+            write_transactions(dir.path(), fetched(vec![undated.clone()], vec![]), dates)
+                .await
+                .unwrap();
+        }
+        assert_eq!(transitions(dir.path()).await, ["appeared"]);
+
+        write_transactions(dir.path(), fetched(vec![], vec![]), dates)
+            .await
+            .unwrap();
+        assert_eq!(transitions(dir.path()).await, ["appeared", "vanished"]);
+    }
+
+    #[tokio::test]
+    async fn pending_vanishes_from_month_with_nothing_fetched() {
+        let dir = tempfile::tempdir().unwrap();
+        let dates = DateRange {
+            start: NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(),
+            end: NaiveDate::from_ymd_opt(2024, 4, 15).unwrap(),
+        };
+        let pending = tx(json!({"transactionId": "p", "bookingDate": "2024-03-05"}));
+
+        write_transactions(dir.path(), fetched(vec![pending], vec![]), dates)
+            .await
+            .unwrap();
+        write_transactions(dir.path(), fetched(vec![], vec![]), dates)
+            .await
+            .unwrap();
+
+        assert_eq!(transitions(dir.path()).await, ["appeared", "vanished"]);
+        assert!(!dir.path().join("2024-04.jsonl").exists());
+    }
+
+    #[test]
+    fn plan_only_allows_agreed_scopes() {
+        let dates = DateRange {
+            start: NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(),
+            end: NaiveDate::from_ymd_opt(2024, 3, 15).unwrap(),
+        };
+        let unknown = FetchPlan {
+            dates,
+            access_scope: None,
+        };
+        let agreed = FetchPlan {
+            dates,
+            access_scope: Some(&[AccessScope::Transactions]),
+        };
+
+        assert!(unknown.allows(AccessScope::Balances));
+        assert!(agreed.allows(AccessScope::Transactions));
+        assert!(!agreed.allows(AccessScope::Balances));
+        assert!(!agreed.allows(AccessScope::Details));
+    }
+
+    #[test]
+    fn merges_on_transaction_id() {
+        let merged = merge_transactions(
+            vec![booked(
+                json!({"transactionId": "a", "bookingDate": "2024-01-02"}),
+            )],
+            vec![
+                booked(json!({"transactionId": "a", "bookingDate": "2024-01-03"})),
+                booked(json!({"transactionId": "b", "bookingDate": "2024-01-04"})),
+            ],
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(merged.len(), 2);
+        assert_eq!(
+            merged[0].transaction().booking_date,
+            NaiveDate::from_ymd_opt(2024, 1, 3)
+        );
+    }
+
+    #[test]
+    fn merges_on_internal_transaction_id() {
+        let merged = merge_transactions(
+            vec![booked(
+                json!({"internalTransactionId": "x", "valueDate": "2024-01-02"}),
+            )],
+            vec![booked(
+                json!({"internalTransactionId": "x", "valueDate": "2024-01-03"}),
+            )],
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(merged.len(), 1);
+        assert_eq!(
+            merged[0].transaction().value_date,
+            NaiveDate::from_ymd_opt(2024, 1, 3)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_content_hash() {
+        let existing = json!({"bookingDate": "2024-01-02", "note": "coffee"});
+        let merged = merge_transactions(
+            vec![booked(existing.clone())],
+            vec![
+                booked(existing),
+                booked(json!({"bookingDate": "2024-01-02", "note": "tea"})),
+            ],
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(merged.len(), 2);
+    }
+
+    #[test]
+    fn keeps_identical_transactions_from_one_fetch() {
+        let coffee = json!({"bookingDate": "2024-01-02", "note": "coffee"});
+        let twice = || vec![booked(coffee.clone()), booked(coffee.clone())];
+
+        let merged = merge_transactions(vec![], twice(), false).unwrap();
+        assert_eq!(merged.len(), 2);
+
+        let merged = merge_transactions(vec![booked(coffee.clone())], twice(), false).unwrap();
+        assert_eq!(merged.len(), 2);
+
+        let merged = merge_transactions(twice(), twice(), false).unwrap();
+        assert_eq!(merged.len(), 2);
+    }
+
+    #[test]
+    fn booked_replaces_pending_that_gained_a_transaction_id() {
+        let merged = merge_transactions(
+            vec![pending(json!({"internalTransactionId": "x"}))],
+            vec![booked(
+                json!({"transactionId": "a", "internalTransactionId": "x"}),
+            )],
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(statuses(&merged), vec![(false, Some("a"))]);
+    }
+
+    #[test]
+    fn pending_never_replaces_booked() {
+        let merged = merge_transactions(
+            vec![booked(
+                json!({"transactionId": "a", "internalTransactionId": "x"}),
+            )],
+            vec![pending(json!({"internalTransactionId": "x"}))],
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(statuses(&merged), vec![(false, Some("a"))]);
+    }
+
+    #[test]
+    fn drops_stale_pending_when_month_is_covered() {
+        let merged = merge_transactions(
+            vec![
+                pending(json!({"transactionId": "gone"})),
+                booked(json!({"transactionId": "a"})),
+            ],
+            vec![pending(json!({"transactionId": "new"}))],
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(
+            statuses(&merged),
+            vec![(false, Some("a")), (true, Some("new"))]
+        );
+    }
+}
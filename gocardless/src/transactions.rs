@@ -1,6 +1,7 @@
 use std::sync::LazyLock;
 
 use chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use tzfile::Tz;
 
@@ -30,7 +31,7 @@ pub(crate) struct TransactionsInner {
 static EUROPE_LONDON: LazyLock<Tz> =
     LazyLock::new(|| Tz::named("Europe/London").expect("Europe/London timezone"));
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct Transaction {
     #[serde(
         rename = "bookingDate",
@@ -68,6 +69,14 @@ pub(crate) struct Transaction {
     pub(crate) other: serde_json::Value,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TransactionAmount {
+    pub(crate) amount: Decimal,
+    pub(crate) currency: String,
+    #[serde(flatten)]
+    pub(crate) other: serde_json::Value,
+}
+
 impl Transaction {
     pub(crate) fn date_best_effort(&self) -> Option<NaiveDate> {
         self.booking_date
@@ -99,4 +108,34 @@ impl Transaction {
                 })
             })
     }
+
+    // These stay in `other` rather than becoming typed fields, so that
+    // transactions are written back with their keys in the same order.
+    pub(crate) fn transaction_amount(&self) -> Option<TransactionAmount> {
+        serde_json::from_value(self.other.get("transactionAmount")?.clone()).ok()
+    }
+
+    pub(crate) fn remittance_information_unstructured(&self) -> Option<&str> {
+        self.other
+            .get("remittanceInformationUnstructured")?
+            .as_str()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_without_reordering() {
+        let line = r#"{"bookingDate":"2024-01-02","transactionId":"a","creditorName":"Cafe","remittanceInformationUnstructured":"COFFEE","transactionAmount":{"amount":"-2.50","currency":"GBP"}}"#;
+        let tx: Transaction = serde_json::from_str(line).unwrap();
+
+        assert_eq!(serde_json::to_string(&tx).unwrap(), line);
+        assert_eq!(tx.remittance_information_unstructured(), Some("COFFEE"));
+        assert_eq!(
+            tx.transaction_amount().map(|amount| amount.amount),
+            Some(Decimal::new(-250, 2))
+        );
+    }
 }
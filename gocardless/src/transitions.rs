@@ -0,0 +1,454 @@
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+
+use crate::{
+    files::{read_json_lines, write_json_lines},
+    sync::TransactionWithStatus,
+    transactions::Transaction,
+};
+
+// How far apart the pending and booked dates may be for us to consider them
+// the same payment when the bank gives the booked one a fresh id.
+const MAX_BOOKING_DELAY_DAYS: i64 = 7;
+
+/// One line of an account's `transitions.jsonl`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Transition {
+    pub(crate) at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub(crate) event: TransitionEvent,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum TransitionEvent {
+    Appeared {
+        pending: Transaction,
+    },
+    Amended {
+        before: Transaction,
+        after: Transaction,
+    },
+    Booked {
+        matched_by: MatchedBy,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        first_seen: Option<DateTime<Utc>>,
+        pending: Transaction,
+        booked: Transaction,
+    },
+    Vanished {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        first_seen: Option<DateTime<Utc>>,
+        pending: Transaction,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MatchedBy {
+    Id,
+    AmountDateDescription,
+}
+
+/// What we knew before this sync, and what the bank told us this time, for
+/// the months that were fetched in full.
+#[derive(Debug, Default)]
+pub(crate) struct Observations {
+    pub(crate) previous_pending: Vec<Transaction>,
+    pub(crate) previous_booked: Vec<Transaction>,
+    pub(crate) fetched_pending: Vec<Transaction>,
+    pub(crate) fetched_booked: Vec<Transaction>,
+}
+
+impl Observations {
+    /// Notes what a bucket (eg: a month file) held before, and what the
+    /// bank returned for it this time.
+    pub(crate) fn observe(
+        &mut self,
+        previous: &[TransactionWithStatus],
+        fetched: &[TransactionWithStatus],
+    ) {
+        for (txs, pending, booked) in [
+            (
+                previous,
+                &mut self.previous_pending,
+                &mut self.previous_booked,
+            ),
+            (fetched, &mut self.fetched_pending, &mut self.fetched_booked),
+        ] {
+            for tx in txs {
+                match tx {
+                    TransactionWithStatus::Pending(tx) => pending.push(tx.clone()),
+                    TransactionWithStatus::Booked(tx) => booked.push(tx.clone()),
+                }
+            }
+        }
+    }
+
+    /// Works out what happened to each pending transaction since the last
+    /// sync. Pending transactions that neither persist nor match a newly
+    /// booked transaction are presumed to have vanished.
+    pub(crate) fn transitions(self) -> Vec<TransitionEvent> {
+        let Observations {
+            previous_pending,
+            previous_booked,
+            fetched_pending,
+            fetched_booked,
+        } = self;
+
+        let mut newly_booked = fetched_booked
+            .into_iter()
+            .filter(|booked| {
+                !previous_booked
+                    .iter()
+                    .any(|prev| same_transaction(prev, booked))
+            })
+            .map(Some)
+            .collect::<Vec<_>>();
+
+        let mut events = Vec::new();
+        for pending in previous_pending.iter() {
+            if let Some(current) = fetched_pending
+                .iter()
+                .find(|current| same_transaction(pending, current))
+            {
+                if pending.transaction_amount() != current.transaction_amount() {
+                    events.push(TransitionEvent::Amended {
+                        before: pending.clone(),
+                        after: current.clone(),
+                    });
+                }
+                continue;
+            }
+
+            let matched = take_match(&mut newly_booked, |booked| {
+                shares_id(pending, booked).then_some(0)
+            })
+            .map(|booked| (MatchedBy::Id, booked))
+            .or_else(|| {
+                take_match(&mut newly_booked, |booked| fuzzy_distance(pending, booked))
+                    .map(|booked| (MatchedBy::AmountDateDescription, booked))
+            });
+
+            events.push(match matched {
+                Some((matched_by, booked)) => TransitionEvent::Booked {
+                    matched_by,
+                    first_seen: None,
+                    pending: pending.clone(),
+                    booked,
+                },
+                None => TransitionEvent::Vanished {
+                    first_seen: None,
+                    pending: pending.clone(),
+                },
+            });
+        }
+
+        for pending in fetched_pending {
+            if !previous_pending
+                .iter()
+                .any(|prev| same_transaction(prev, &pending))
+            {
+                events.push(TransitionEvent::Appeared { pending });
+            }
+        }
+
+        debug!(count = events.len(), "Detected pending transitions");
+
+        events
+    }
+}
+
+/// Appends events to the account's transition log, noting when each settled
+/// or vanished pending transaction was first seen.
+#[instrument(skip_all, fields(?path))]
+pub(crate) async fn record_transitions(
+    path: &Path,
+    events: Vec<TransitionEvent>,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let mut log = read_json_lines::<Transition>(path).await?;
+
+    for mut event in events {
+        if let TransitionEvent::Booked {
+            first_seen,
+            pending,
+            ..
+        }
+        | TransitionEvent::Vanished {
+            first_seen,
+            pending,
+        } = &mut event
+        {
+            *first_seen = first_appeared(&log, pending);
+        }
+        log.push(Transition { at: now, event });
+    }
+
+    write_json_lines(path, log).await
+}
+
+fn first_appeared(log: &[Transition], pending: &Transaction) -> Option<DateTime<Utc>> {
+    log.iter()
+        .filter(|transition| match &transition.event {
+            TransitionEvent::Appeared { pending: seen } => same_transaction(seen, pending),
+            _ => false,
+        })
+        .map(|transition| transition.at)
+        .min()
+}
+
+// Removes and returns the candidate with the lowest score, if any match.
+fn take_match(
+    candidates: &mut [Option<Transaction>],
+    score: impl Fn(&Transaction) -> Option<i64>,
+) -> Option<Transaction> {
+    let (best, _) = candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(i, candidate)| Some((i, score(candidate.as_ref()?)?)))
+        .min_by_key(|(_, score)| *score)?;
+    candidates[best].take()
+}
+
+fn same_transaction(a: &Transaction, b: &Transaction) -> bool {
+    match (has_id(a), has_id(b)) {
+        (true, true) => shares_id(a, b),
+        (false, false) => {
+            a.transaction_amount() == b.transaction_amount()
+                && a.remittance_information_unstructured()
+                    == b.remittance_information_unstructured()
+                && a.date_best_effort() == b.date_best_effort()
+                && a.other == b.other
+        }
+        _ => false,
+    }
+}
+
+fn has_id(tx: &Transaction) -> bool {
+    tx.transaction_id.is_some() || tx.internal_transaction_id.is_some()
+}
+
+fn shares_id(a: &Transaction, b: &Transaction) -> bool {
+    let same = |a: &Option<String>, b: &Option<String>| a.is_some() && a == b;
+    same(&a.transaction_id, &b.transaction_id)
+        || same(&a.internal_transaction_id, &b.internal_transaction_id)
+}
+
+// Returns how many days apart the two are, if they otherwise look like the
+// same payment.
+fn fuzzy_distance(pending: &Transaction, booked: &Transaction) -> Option<i64> {
+    let amount = pending.transaction_amount()?;
+    if Some(amount) != booked.transaction_amount() {
+        return None;
+    }
+
+    let days = days_between(pending.date_best_effort()?, booked.date_best_effort()?);
+    if days > MAX_BOOKING_DELAY_DAYS {
+        return None;
+    }
+
+    similar_description(
+        pending.remittance_information_unstructured(),
+        booked.remittance_information_unstructured(),
+    )
+    .then_some(days)
+}
+
+fn days_between(a: NaiveDate, b: NaiveDate) -> i64 {
+    (b - a).num_days().abs()
+}
+
+// Banks often decorate the booked description (card suffixes, locations, and
+// so on), so we only ask that one be contained in the other.
+fn similar_description(a: Option<&str>, b: Option<&str>) -> bool {
+    let normalise = |s: &str| {
+        s.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect::<String>()
+    };
+    match (a.map(normalise), b.map(normalise)) {
+        (Some(a), Some(b)) if !a.is_empty() && !b.is_empty() => a.contains(&b) || b.contains(&a),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tx(value: serde_json::Value) -> Transaction {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn payment(id: Option<&str>, amount: &str, date: &str, description: &str) -> Transaction {
+        let mut value = json!({
+            "bookingDate": date,
+            "transactionAmount": {"amount": amount, "currency": "GBP"},
+            "remittanceInformationUnstructured": description,
+        });
+        if let Some(id) = id {
+            value["transactionId"] = json!(id);
+        }
+        tx(value)
+    }
+
+    fn kinds(events: &[TransitionEvent]) -> Vec<&'static str> {
+        events
+            .iter()
+            .map(|event| match event {
+                TransitionEvent::Appeared { .. } => "appeared",
+                TransitionEvent::Amended { .. } => "amended",
+                TransitionEvent::Booked {
+                    matched_by: MatchedBy::Id,
+                    ..
+                } => "booked_by_id",
+                TransitionEvent::Booked {
+                    matched_by: MatchedBy::AmountDateDescription,
+                    ..
+                } => "booked_by_match",
+                TransitionEvent::Vanished { .. } => "vanished",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn books_pending_by_id() {
+        let events = Observations {
+            previous_pending: vec![payment(Some("a"), "-3.50", "2024-03-01", "COFFEE")],
+            fetched_booked: vec![payment(Some("a"), "-3.50", "2024-03-02", "COFFEE")],
+            ..Default::default()
+        }
+        .transitions();
+
+        assert_eq!(kinds(&events), ["booked_by_id"]);
+    }
+
+    #[test]
+    fn books_pending_by_amount_date_and_description() {
+        let events = Observations {
+            previous_pending: vec![payment(Some("p1"), "-3.50", "2024-03-01", "COFFEE")],
+            fetched_booked: vec![
+                payment(Some("b0"), "-3.50", "2024-03-20", "COFFEE"),
+                payment(Some("b1"), "-3.50", "2024-03-03", "COFFEE SHOP LONDON"),
+                payment(Some("b2"), "-4.00", "2024-03-01", "COFFEE"),
+            ],
+            ..Default::default()
+        }
+        .transitions();
+
+        assert_eq!(kinds(&events), ["booked_by_match"]);
+        let TransitionEvent::Booked { booked, .. } = &events[0] else {
+            unreachable!()
+        };
+        assert_eq!(booked.transaction_id.as_deref(), Some("b1"));
+    }
+
+    #[test]
+    fn unmatched_pending_vanishes() {
+        let events = Observations {
+            previous_pending: vec![payment(Some("p1"), "-3.50", "2024-03-01", "COFFEE")],
+            fetched_booked: vec![payment(Some("b1"), "-3.50", "2024-03-02", "GROCER")],
+            ..Default::default()
+        }
+        .transitions();
+
+        assert_eq!(kinds(&events), ["vanished"]);
+    }
+
+    #[test]
+    fn notices_amended_and_appeared_pendings() {
+        let events = Observations {
+            previous_pending: vec![payment(Some("a"), "-3.50", "2024-03-01", "COFFEE")],
+            fetched_pending: vec![
+                payment(Some("a"), "-4.50", "2024-03-01", "COFFEE"),
+                payment(Some("b"), "-1.00", "2024-03-01", "BUS"),
+            ],
+            ..Default::default()
+        }
+        .transitions();
+
+        assert_eq!(kinds(&events), ["amended", "appeared"]);
+    }
+
+    #[test]
+    fn idless_pendings_differ_by_amount_and_description() {
+        let coffee = payment(None, "-3.50", "2024-03-01", "COFFEE");
+        let events = Observations {
+            previous_pending: vec![coffee.clone()],
+            fetched_pending: vec![
+                coffee,
+                payment(None, "-4.00", "2024-03-01", "COFFEE"),
+                payment(None, "-3.50", "2024-03-01", "BUS"),
+            ],
+            ..Default::default()
+        }
+        .transitions();
+
+        assert_eq!(kinds(&events), ["appeared", "appeared"]);
+    }
+
+    #[test]
+    fn ignores_previously_booked() {
+        let booked = payment(Some("b1"), "-3.50", "2024-03-02", "COFFEE");
+        let events = Observations {
+            previous_pending: vec![payment(Some("p1"), "-3.50", "2024-03-01", "COFFEE")],
+            previous_booked: vec![booked.clone()],
+            fetched_booked: vec![booked],
+            ..Default::default()
+        }
+        .transitions();
+
+        assert_eq!(kinds(&events), ["vanished"]);
+    }
+
+    #[tokio::test]
+    async fn records_when_pending_first_appeared() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transitions.jsonl");
+        let pending = payment(Some("a"), "-3.50", "2024-03-01", "COFFEE");
+        let appeared_at = "2024-03-01T12:00:00Z".parse().unwrap();
+        let booked_at = "2024-03-02T12:00:00Z".parse().unwrap();
+
+        record_transitions(
+            &path,
+            vec![TransitionEvent::Appeared {
+                pending: pending.clone(),
+            }],
+            appeared_at,
+        )
+        .await
+        .unwrap();
+        record_transitions(
+            &path,
+            vec![TransitionEvent::Vanished {
+                first_seen: None,
+                pending,
+            }],
+            booked_at,
+        )
+        .await
+        .unwrap();
+
+        let log = read_json_lines::<Transition>(&path).await.unwrap();
+        assert_eq!(log.len(), 2);
+        assert!(matches!(
+            log[1].event,
+            TransitionEvent::Vanished {
+                first_seen: Some(first_seen),
+                ..
+            } if first_seen == appeared_at
+        ));
+    }
+}
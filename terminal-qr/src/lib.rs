@@ -0,0 +1,34 @@
+//! QR codes drawn with Unicode block characters, for when a link needs to
+//! get from a terminal to a phone.
+
+use qrcode::{render::unicode, types::QrError, QrCode};
+
+/// Renders `value` as a QR code, two modules per character cell.
+///
+/// Light and dark are swapped, as most terminals draw light text on dark.
+pub fn render(value: &str) -> Result<String, QrError> {
+    let code = QrCode::new(value)?;
+    let image = code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build();
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_square_block_image() {
+        let image = render("https://example.com/").unwrap();
+        let lines = image.lines().collect::<Vec<_>>();
+        let width = lines[0].chars().count();
+
+        assert!(lines.iter().all(|line| line.chars().count() == width));
+        // Two modules per character cell vertically.
+        assert_eq!(lines.len(), width.div_ceil(2));
+    }
+}
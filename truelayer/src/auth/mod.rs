@@ -1,6 +1,9 @@
-use std::{net::IpAddr, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use axum::{
     http::uri::{Scheme, Uri},
     response::{IntoResponse, Response},
@@ -8,12 +11,17 @@ use axum::{
 };
 use tokio::net::TcpListener;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::{ClientCreds, Environment, ProviderConfig, TlClient};
+use crate::{
+    sync_account_extras, AccountExtras, ClientCreds, Environment, HttpListenerConfig,
+    ProviderConfig, TlClient, TokenKey,
+};
 
 mod start;
 
+const DEFAULT_LISTEN_PORT: u16 = 5500;
+
 struct WebError(anyhow::Error);
 
 type WebResult<T> = std::result::Result<T, WebError>;
@@ -23,36 +31,78 @@ pub async fn authenticate(
     environment: Environment,
     provider: &ProviderConfig,
     client_creds: &ClientCreds,
-    listen_port: u16,
+    token_key: Option<&TokenKey>,
+    http: Option<&HttpListenerConfig>,
+    listen_port: Option<u16>,
 ) -> Result<()> {
     let cnx = CancellationToken::new();
     let tl = Arc::new(TlClient::new(
         client.clone(),
         environment,
         &provider.user_token,
+        token_key.cloned(),
         client_creds,
     ));
 
-    let ip_addr = IpAddr::from([127, 0, 0, 1]);
-    let listener = TcpListener::bind((ip_addr, listen_port))
+    let bind_address = match (http, listen_port) {
+        (Some(http), Some(port)) => SocketAddr::new(http.bind_address.ip(), port),
+        (Some(http), None) => http.bind_address,
+        (None, port) => SocketAddr::new(
+            IpAddr::from([127, 0, 0, 1]),
+            port.unwrap_or(DEFAULT_LISTEN_PORT),
+        ),
+    };
+    let listener = TcpListener::bind(bind_address)
         .await
-        .with_context(|| format!("Bind to address: {}:{}", ip_addr, listen_port))?;
+        .with_context(|| format!("Bind to address: {}", bind_address))?;
 
-    let listen_address = listener.local_addr().context("listen address")?;
-    let base_url = Uri::builder()
-        .scheme(Scheme::HTTP)
-        .authority(listen_address.to_string())
-        .path_and_query("")
-        .build()
-        .context("Build base URI")?;
-    let app = Router::new().merge(start::routes(cnx.clone(), tl.clone(), base_url));
+    let base_url = match http {
+        Some(http) => http.client_facing_url()?,
+        None => {
+            let listen_address = listener.local_addr().context("listen address")?;
+            Uri::builder()
+                .scheme(Scheme::HTTP)
+                .authority(listen_address.to_string())
+                .path_and_query("/")
+                .build()
+                .context("Build base URI")?
+        }
+    };
+    let start_url = base_url.to_string();
+    let failure = Arc::new(Mutex::new(None));
+    let app = Router::new().merge(start::routes(
+        cnx.clone(),
+        tl.clone(),
+        provider.clone(),
+        base_url,
+        failure.clone(),
+    ));
 
-    eprintln!("Please visit http://{}/", listen_address,);
+    eprintln!("Please visit {}", start_url);
+    eprintln!(
+        "{}",
+        terminal_qr::render(&start_url).context("Encode QR code")?
+    );
 
     axum::serve(listener, app)
         .with_graceful_shutdown(cnx.clone().cancelled_owned())
         .await
         .context("Running server")?;
+
+    if let Some(failure) = failure.lock().expect("failure lock").take() {
+        bail!("Authentication failed: {}", failure);
+    }
+
+    // This is the one time we know the user has only just authenticated.
+    // The token is stored by now, so failing here should not fail `auth`.
+    let extras = AccountExtras::from_config(provider);
+    if extras.any() {
+        let target_dir = Arc::from(provider.target_dir.clone().into_boxed_path());
+        if let Err(error) = sync_account_extras(tl, target_dir, extras).await {
+            warn!(?error, "Fetching standing orders and direct debits failed");
+        }
+    }
+
     info!("Done!");
     Ok(())
 }
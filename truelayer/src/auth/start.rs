@@ -1,29 +1,39 @@
-use std::{borrow::Cow, collections::HashMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::{anyhow, Context, Result};
 use askama::Template;
 use axum::{
     extract::{Query, State},
-    http::uri,
+    http::{uri, StatusCode},
     response::{Html, IntoResponse, Response},
     routing::get,
     Router,
 };
 use hyper::Uri;
+use rand::{distributions::Alphanumeric, Rng};
 use secrecy::SecretString;
 use serde::Deserialize;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
-use crate::{auth::WebResult, Environment, TlClient};
+use crate::{auth::WebResult, Environment, ProviderConfig, TlClient};
 
 use super::WebError;
 
 #[derive(Clone)]
 pub(crate) struct Start {
     client: Arc<TlClient>,
+    provider: ProviderConfig,
     base_url: Uri,
     cnx: CancellationToken,
+    // Sent with the authorisation request, and checked on the redirect back,
+    // so that we only accept codes from a flow that we started.
+    session_state: Arc<str>,
+    failure: Arc<Mutex<Option<String>>>,
 }
 
 #[derive(Template)]
@@ -32,23 +42,48 @@ struct StartTemplate {
     url: hyper::Uri,
 }
 
+#[derive(Template)]
+#[template(path = "auth_error.html")]
+struct ErrorTemplate {
+    error: String,
+    description: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
-struct RedirectToken {
-    code: SecretString,
-    // Also state, scope
+struct RedirectQuery {
+    code: Option<SecretString>,
+    state: Option<String>,
+    scope: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
 }
 
 #[derive(Debug)]
 struct AskamaTemplate<T>(T);
 
-pub(crate) fn routes(cnx: CancellationToken, client: Arc<TlClient>, base_url: Uri) -> Router {
+pub(crate) fn routes(
+    cnx: CancellationToken,
+    client: Arc<TlClient>,
+    provider: ProviderConfig,
+    base_url: Uri,
+    failure: Arc<Mutex<Option<String>>>,
+) -> Router {
+    let session_state = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect::<String>();
+
     Router::new()
         .route("/", get(Start::index))
         .route("/start-redirect", get(Start::redirect))
         .with_state(Start {
             client,
+            provider,
             base_url,
             cnx,
+            session_state: session_state.into(),
+            failure,
         })
 }
 
@@ -64,24 +99,24 @@ impl Start {
             Environment::Live => "auth.truelayer.com",
         };
 
-        let providers = match self.client.env() {
-            Environment::Sandbox => "uk-cs-mock uk-ob-all uk-oauth-all",
-            Environment::Live => "uk-ob-all uk-oauth-all",
-        };
         let redirect_url = self.redirect_uri()?;
 
         info!(%redirect_url);
 
-        let query = HashMap::<&str, Cow<'_, str>>::from([
-        ("response_type", "code".into()),
-        ("client_id", self.client.client_id().into()),
-        ("redirect_uri", redirect_url.to_string().into() ),
-        (
-            "scope",
-            "info accounts balance cards transactions direct_debits standing_orders offline_access".into(),
-        ),
-        ("providers", providers.into(),),
-    ]);
+        let mut query = HashMap::<&str, Cow<'_, str>>::from([
+            ("response_type", "code".into()),
+            ("client_id", self.client.client_id().into()),
+            ("redirect_uri", redirect_url.to_string().into()),
+            ("scope", self.provider.auth_scopes().into()),
+            ("state", (*self.session_state).into()),
+            (
+                "providers",
+                self.provider.auth_providers(self.client.env()).into(),
+            ),
+        ]);
+        if let Some(provider_id) = &self.provider.provider_id {
+            query.insert("provider_id", provider_id.into());
+        }
         let qs = serde_urlencoded::to_string(query).context("encode query")?;
         let u = uri::Builder::new()
             .scheme("https")
@@ -107,7 +142,10 @@ impl Start {
                     .cloned()
                     .ok_or(anyhow!("Base URL missing authority: {}", self.base_url))?,
             )
-            .path_and_query("/start-redirect")
+            .path_and_query(format!(
+                "{}/start-redirect",
+                self.base_url.path().trim_end_matches('/')
+            ))
             .build()
             .context("Build redirect URI")?;
         Ok(uri)
@@ -115,21 +153,50 @@ impl Start {
 
     async fn redirect(
         State(state): State<Start>,
-        Query(RedirectToken { code }): Query<RedirectToken>,
-    ) -> WebResult<impl IntoResponse> {
-        Ok(state.handle_redirect(code).await?)
+        Query(query): Query<RedirectQuery>,
+    ) -> WebResult<Response> {
+        Ok(state.handle_redirect(query).await?)
     }
 
-    async fn handle_redirect(&self, code: SecretString) -> Result<impl IntoResponse> {
+    async fn handle_redirect(&self, query: RedirectQuery) -> Result<Response> {
+        if query.state.as_deref() != Some(&*self.session_state) {
+            warn!("Redirect state does not match this session; ignoring");
+            let template = ErrorTemplate {
+                error: "state mismatch".to_owned(),
+                description: Some("This link is not from the current sign in attempt.".to_owned()),
+            };
+            return Ok((StatusCode::BAD_REQUEST, AskamaTemplate(template)).into_response());
+        }
+
+        if let Some(error) = query.error {
+            warn!(%error, description=?query.error_description, "Authentication failed");
+            let message = match &query.error_description {
+                Some(description) => format!("{}: {}", error, description),
+                None => error.clone(),
+            };
+            *self.failure.lock().expect("failure lock") = Some(message);
+            self.cnx.cancel();
+
+            let template = ErrorTemplate {
+                error,
+                description: query.error_description,
+            };
+            return Ok(AskamaTemplate(template).into_response());
+        }
+
+        let code = query
+            .code
+            .ok_or_else(|| anyhow!("Redirect carried neither a code nor an error"))?;
+
         let redirect_uri = self.redirect_uri()?;
-        debug!("Got code; authenticating…");
+        debug!(scope=?query.scope, "Got code; authenticating…");
         self.client
-            .authenticate(code, &redirect_uri.to_string())
+            .authenticate(code, &redirect_uri.to_string(), query.scope)
             .await
             .context("Authenticate to Truelayer")?;
         info!("Authenticated! Shutting down server");
         self.cnx.cancel();
-        Ok("Done!")
+        Ok("Done!".into_response())
     }
 }
 
@@ -1,8 +1,4 @@
-use std::{
-    fs::File,
-    io::{ErrorKind, Write},
-    path::PathBuf,
-};
+use std::{fmt, io::ErrorKind, path::PathBuf};
 
 use again::RetryPolicy;
 use anyhow::{anyhow, bail, Result};
@@ -10,13 +6,15 @@ use chrono::{DateTime, Duration, Utc};
 use reqwest::Client;
 use secrecy::{Secret, SecretString};
 use serde::{Deserialize, Serialize};
-use tempfile::NamedTempFile;
 use tokio::{sync::Mutex, task::spawn_blocking};
-use tracing::{debug, info, instrument, trace, Span};
+use tracing::{debug, info, instrument, trace, warn, Span};
 
+use super::token_store::{self, TokenKey};
 use crate::Environment;
 use crate::{perform_request, serialize_optional_secret, serialize_secret};
 
+pub(crate) const TOKEN_PATH: &str = "/connect/token";
+
 #[derive(Debug, Serialize, Deserialize)]
 enum GrantType {
     #[serde(rename = "authorization_code")]
@@ -54,6 +52,23 @@ pub struct FetchAccessTokenResponse {
     scope: Option<String>,
 }
 
+/// An error response from the token endpoint, eg: `invalid_grant` once the
+/// user's consent has lapsed.
+#[derive(Debug, Deserialize)]
+pub(crate) struct OAuthError {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// The stored refresh token is no longer any good; the user needs to go
+/// through `auth` again.
+#[derive(Debug)]
+pub struct ReauthRequired {
+    pub token_path: PathBuf,
+    pub reason: String,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ClientCreds {
     id: String,
@@ -64,6 +79,7 @@ pub(crate) struct Authenticator {
     client: Client,
     env: Environment,
     token_path: PathBuf,
+    token_key: Option<TokenKey>,
     credentials: ClientCreds,
     cached_auth_data: Mutex<Option<AuthData>>,
     retry_policy: RetryPolicy,
@@ -81,6 +97,12 @@ pub struct AuthData {
     redirect_uri: String,
     #[serde(default)]
     authed_at: Option<DateTime<Utc>>,
+    // As reported on the redirect back from TrueLayer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    granted_scope: Option<String>,
+    // Set when a refresh was refused, so we do not keep trying.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reauth_required: Option<String>,
 }
 
 impl Authenticator {
@@ -88,6 +110,7 @@ impl Authenticator {
         client: Client,
         env: Environment,
         token_path: PathBuf,
+        token_key: Option<TokenKey>,
         credentials: &ClientCreds,
     ) -> Authenticator {
         let retry_policy =
@@ -97,6 +120,7 @@ impl Authenticator {
             client,
             env,
             token_path,
+            token_key,
             credentials: credentials.clone(),
             cached_auth_data: Mutex::new(None),
             retry_policy,
@@ -111,6 +135,7 @@ impl Authenticator {
         &self,
         access_code: Secret<String>,
         redirect_uri: &str,
+        granted_scope: Option<String>,
     ) -> Result<()> {
         let fetched_at = Utc::now();
         let token_response = self.fetch_access_token(&access_code, redirect_uri).await?;
@@ -120,6 +145,7 @@ impl Authenticator {
             AuthData::from_response(token_response, fetched_at, redirect_uri.to_owned())?;
 
         state.authed_at = Some(fetched_at);
+        state.granted_scope = granted_scope;
 
         self.write_auth_data(&state).await?;
 
@@ -139,6 +165,10 @@ impl Authenticator {
         }
         let data = self.read_auth_data().await?;
 
+        if let Some(reason) = &data.reauth_required {
+            return Err(self.reauth_required(reason.clone()).into());
+        }
+
         if !data.is_expired(at) {
             trace!("Re-used read access token");
             *cached_auth_data = Some(data.clone());
@@ -146,13 +176,43 @@ impl Authenticator {
         }
 
         debug!("Access token expired, refreshing");
-        let data = self.refresh_access_token(&data, at).await?;
+        let data = match self.refresh_access_token(&data, at).await {
+            Ok(refreshed) => refreshed,
+            Err(error) => {
+                let reason = match error.downcast_ref::<OAuthError>() {
+                    Some(oauth_error) if oauth_error.is_invalid_grant() => oauth_error.to_string(),
+                    // Anything else (eg: `invalid_client`) means our own
+                    // configuration is at fault, which re-authenticating
+                    // would not fix.
+                    _ => return Err(error),
+                };
+                warn!(%reason, "Refresh refused; re-authentication required");
+                self.write_auth_data(&AuthData {
+                    reauth_required: Some(reason.clone()),
+                    ..data
+                })
+                .await?;
+                return Err(self.reauth_required(reason).into());
+            }
+        };
         self.write_auth_data(&data).await?;
         *cached_auth_data = Some(data.clone());
 
         Ok(data.access_token)
     }
 
+    pub(crate) async fn authed_at(&self) -> Result<Option<DateTime<Utc>>> {
+        let data = self.read_auth_data().await?;
+        Ok(data.authed_at)
+    }
+
+    fn reauth_required(&self, reason: String) -> ReauthRequired {
+        ReauthRequired {
+            token_path: self.token_path.clone(),
+            reason,
+        }
+    }
+
     async fn fetch_access_token(
         &self,
         access_code: &Secret<String>,
@@ -161,7 +221,7 @@ impl Authenticator {
         let url = self
             .env
             .auth_url_builder()
-            .path_and_query("/connect/token")
+            .path_and_query(TOKEN_PATH)
             .build()?;
         let fetch_access_token_request = FetchAccessTokenRequest {
             grant_type: GrantType::AuthorizationCode,
@@ -184,7 +244,7 @@ impl Authenticator {
         let url = self
             .env
             .auth_url_builder()
-            .path_and_query("/connect/token")
+            .path_and_query(TOKEN_PATH)
             .build()?;
         let fetch_access_token_request = FetchAccessTokenRequest {
             grant_type: GrantType::RefreshToken,
@@ -207,9 +267,13 @@ impl Authenticator {
 
     async fn read_auth_data(&self) -> Result<AuthData, anyhow::Error> {
         let token_path = self.token_path.to_owned();
+        let token_key = self.token_key.clone();
 
-        let data: AuthData = spawn_blocking(move || match File::open(&token_path) {
-            Ok(f) => Ok(serde_json::from_reader(f)?),
+        let data: AuthData = spawn_blocking(move || match std::fs::read(&token_path) {
+            Ok(buf) => {
+                let plaintext = token_store::unseal(&buf, token_key.as_ref(), &token_path)?;
+                Ok(serde_json::from_slice(&plaintext)?)
+            }
             Err(e) if e.kind() == ErrorKind::NotFound => {
                 bail!("No cached authentication token: {:?}", token_path)
             }
@@ -225,14 +289,21 @@ impl Authenticator {
     async fn write_auth_data(&self, state: &AuthData) -> Result<()> {
         let state = state.clone();
         let token_path = self.token_path.to_owned();
+        let token_key = self.token_key.clone();
         let span = Span::current();
         spawn_blocking(move || {
             let _entered = span.enter();
-            let mut tmpf = NamedTempFile::new_in(".")?;
-            serde_json::to_writer_pretty(&mut tmpf, &state)?;
-            tmpf.as_file_mut().flush()?;
-            tmpf.persist(&token_path)?;
-            debug!(?token_path, "Stored auth data");
+            let plaintext = serde_json::to_vec_pretty(&state)?;
+            let contents = match &token_key {
+                Some(key) => token_store::seal(&plaintext, key)?,
+                None => plaintext,
+            };
+            token_store::write_private(&token_path, &contents)?;
+            debug!(
+                ?token_path,
+                encrypted = token_key.is_some(),
+                "Stored auth data"
+            );
             Ok(())
         })
         .await?
@@ -263,6 +334,8 @@ impl AuthData {
             refresh_token,
             redirect_uri,
             authed_at: None,
+            granted_scope: None,
+            reauth_required: None,
         };
         Ok(auth_data)
     }
@@ -299,3 +372,33 @@ impl AuthData {
         self.expires_at <= at
     }
 }
+
+impl OAuthError {
+    // The refresh token was revoked or has expired, along with the consent.
+    fn is_invalid_grant(&self) -> bool {
+        self.error == "invalid_grant"
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error_description {
+            Some(description) => write!(f, "{}: {}", self.error, description),
+            None => f.write_str(&self.error),
+        }
+    }
+}
+
+impl std::error::Error for OAuthError {}
+
+impl fmt::Display for ReauthRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Re-authentication required for {:?}: {}",
+            self.token_path, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ReauthRequired {}
@@ -9,7 +9,10 @@ use rust_decimal::Decimal;
 use secrecy::{ExposeSecret, Secret};
 use serde::{Deserialize, Serialize};
 
-use crate::{client::authentication::Authenticator, perform_request, ClientCreds};
+use crate::{
+    client::{authentication::Authenticator, TokenKey},
+    perform_request, ClientCreds,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response<T> {
@@ -147,14 +150,49 @@ pub struct TransactionsRunningBalance {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct StandingOrderResult {
+    pub frequency: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub currency: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payee: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_payment_date: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_payment_amount: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_payment_date: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_payment_amount: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_payment_date: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_payment_amount: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<serde_json::Value>,
     #[serde(flatten)]
-    inner: serde_json::Value,
+    pub other: serde_json::Value,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DirectDebitResult {
+    pub direct_debit_id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    pub currency: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_payment_timestamp: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_payment_amount: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<serde_json::Value>,
     #[serde(flatten)]
-    inner: serde_json::Value,
+    pub other: serde_json::Value,
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Serialize, Deserialize)]
@@ -182,10 +220,11 @@ impl TlClient {
         client: reqwest::Client,
         env: Environment,
         token_path: &Path,
+        token_key: Option<TokenKey>,
         credentials: &ClientCreds,
     ) -> Self {
         let token_path = token_path.to_owned();
-        let auth = Authenticator::new(client.clone(), env, token_path, credentials);
+        let auth = Authenticator::new(client.clone(), env, token_path, token_key, credentials);
         let retry_policy = RetryPolicy::exponential(Duration::from_secs(1)).with_jitter(true);
         Self {
             client,
@@ -202,12 +241,27 @@ impl TlClient {
         self.auth.client_id()
     }
 
+    /// Makes sure we hold a usable access token, refreshing it if need be.
+    pub async fn ensure_access(&self) -> Result<()> {
+        self.auth.access_token().await?;
+        Ok(())
+    }
+
+    /// When the user last went through the full authentication flow, as
+    /// opposed to us refreshing the token.
+    pub async fn authed_at(&self) -> Result<Option<DateTime<Utc>>> {
+        self.auth.authed_at().await
+    }
+
     pub async fn authenticate(
         &self,
         access_code: Secret<String>,
         redirect_uri: &str,
+        granted_scope: Option<String>,
     ) -> Result<()> {
-        self.auth.authenticate(access_code, redirect_uri).await?;
+        self.auth
+            .authenticate(access_code, redirect_uri, granted_scope)
+            .await?;
 
         Ok(())
     }
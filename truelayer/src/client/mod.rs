@@ -1,5 +1,8 @@
 mod authentication;
 mod driver;
+mod token_store;
 
-pub use authentication::ClientCreds;
+pub use authentication::{ClientCreds, ReauthRequired};
+pub(crate) use authentication::{OAuthError, TOKEN_PATH};
 pub use driver::{AccountsResult, CardsResult, Environment, TlClient};
+pub use token_store::TokenKey;
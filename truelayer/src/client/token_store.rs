@@ -0,0 +1,329 @@
+use std::{
+    fmt,
+    fs::{File, Permissions},
+    io::Write,
+    num::NonZeroU32,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
+    hkdf, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use secrecy::{ExposeSecret, SecretVec, Zeroize};
+use serde::{Deserialize, Serialize};
+use tempfile::Builder;
+use tracing::{debug, info};
+
+const FORMAT_VERSION: u32 = 1;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+const AAD: &[u8] = b"tl-scraper token";
+
+/// Secret material used to encrypt token files at rest; either a passphrase
+/// or the contents of a key file.
+#[derive(Clone)]
+pub struct TokenKey {
+    secret: Arc<SecretVec<u8>>,
+    kdf: Kdf,
+}
+
+// How the file key is derived from the token key. Passphrases need
+// stretching; a random key file is already as strong as it gets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Kdf {
+    #[default]
+    Pbkdf2,
+    Hkdf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SealedFile {
+    sealed: u32,
+    // Absent from files written before key files switched to HKDF.
+    #[serde(default)]
+    kdf: Kdf,
+    salt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iterations: Option<u32>,
+    nonce: String,
+    ciphertext: String,
+}
+
+// Just enough to tell a sealed file from a plaintext one.
+#[derive(Debug, Deserialize)]
+struct Probe {
+    sealed: Option<u32>,
+}
+
+impl fmt::Debug for TokenKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TokenKey([REDACTED])")
+    }
+}
+
+impl TokenKey {
+    pub(crate) fn passphrase(secret: Vec<u8>) -> Self {
+        TokenKey {
+            secret: Arc::new(SecretVec::new(secret)),
+            kdf: Kdf::Pbkdf2,
+        }
+    }
+
+    pub(crate) fn key_file(secret: Vec<u8>) -> Self {
+        TokenKey {
+            secret: Arc::new(SecretVec::new(secret)),
+            kdf: Kdf::Hkdf,
+        }
+    }
+
+    fn derive(&self, kdf: Kdf, salt: &[u8]) -> Result<LessSafeKey> {
+        let unbound = match kdf {
+            Kdf::Pbkdf2 => {
+                let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).expect("non-zero");
+                let mut key = [0u8; 32];
+                pbkdf2::derive(
+                    pbkdf2::PBKDF2_HMAC_SHA256,
+                    iterations,
+                    salt,
+                    self.secret.expose_secret(),
+                    &mut key,
+                );
+                let unbound = UnboundKey::new(&CHACHA20_POLY1305, &key);
+                key.zeroize();
+                unbound.map_err(|_| anyhow!("Build token key"))?
+            }
+            Kdf::Hkdf => hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+                .extract(self.secret.expose_secret())
+                .expand(&[AAD], &CHACHA20_POLY1305)
+                .map_err(|_| anyhow!("Build token key"))?
+                .into(),
+        };
+        Ok(LessSafeKey::new(unbound))
+    }
+}
+
+/// Encrypts a token file's contents with a key derived from `key` and a
+/// fresh salt.
+pub(crate) fn seal(plaintext: &[u8], key: &TokenKey) -> Result<Vec<u8>> {
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill(&mut salt)
+        .map_err(|_| anyhow!("Generate token salt"))?;
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    rng.fill(&mut nonce)
+        .map_err(|_| anyhow!("Generate token nonce"))?;
+
+    let mut in_out = plaintext.to_vec();
+    key.derive(key.kdf, &salt)?
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(AAD),
+            &mut in_out,
+        )
+        .map_err(|_| anyhow!("Encrypt token"))?;
+
+    let sealed = SealedFile {
+        sealed: FORMAT_VERSION,
+        kdf: key.kdf,
+        salt: STANDARD.encode(salt),
+        iterations: (key.kdf == Kdf::Pbkdf2).then_some(PBKDF2_ITERATIONS),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(in_out),
+    };
+
+    Ok(serde_json::to_vec_pretty(&sealed)?)
+}
+
+/// Returns the plaintext of a token file. Plaintext files are passed through
+/// as is, so turning on encryption takes effect on the next write.
+pub(crate) fn unseal(buf: &[u8], key: Option<&TokenKey>, path: &Path) -> Result<Vec<u8>> {
+    let probe: Probe =
+        serde_json::from_slice(buf).with_context(|| format!("Parse token file: {:?}", path))?;
+    if probe.sealed.is_none() {
+        if key.is_some() {
+            info!(?path, "Token file is not encrypted yet");
+        }
+        return Ok(buf.to_vec());
+    }
+
+    let sealed: SealedFile = serde_json::from_slice(buf)
+        .with_context(|| format!("Parse encrypted token file: {:?}", path))?;
+    if sealed.sealed != FORMAT_VERSION {
+        bail!(
+            "Unsupported token file version {} in {:?}",
+            sealed.sealed,
+            path
+        );
+    }
+    // The count is stored for the day it needs raising; until then, trusting
+    // it would let whoever can write the file make every read arbitrarily
+    // slow.
+    match (sealed.kdf, sealed.iterations) {
+        (Kdf::Pbkdf2, Some(PBKDF2_ITERATIONS)) | (Kdf::Hkdf, None) => {}
+        (kdf, iterations) => bail!(
+            "Unsupported {:?} iteration count {:?} in {:?}",
+            kdf,
+            iterations,
+            path
+        ),
+    }
+    let key = key.ok_or_else(|| {
+        anyhow!(
+            "Token file {:?} is encrypted, but no token_key is configured",
+            path
+        )
+    })?;
+
+    let salt = STANDARD.decode(&sealed.salt).context("Decode token salt")?;
+    let nonce = STANDARD
+        .decode(&sealed.nonce)
+        .context("Decode token nonce")?;
+    let nonce =
+        Nonce::try_assume_unique_for_key(&nonce).map_err(|_| anyhow!("Invalid token nonce"))?;
+    let mut in_out = STANDARD
+        .decode(&sealed.ciphertext)
+        .context("Decode token ciphertext")?;
+
+    let plaintext = key
+        .derive(sealed.kdf, &salt)?
+        .open_in_place(nonce, Aad::from(AAD), &mut in_out)
+        .map_err(|_| {
+            anyhow!(
+                "Decrypt token file {:?}: wrong key, or the file is corrupt",
+                path
+            )
+        })?;
+
+    Ok(plaintext.to_vec())
+}
+
+/// Atomically replaces `path` with `contents`, readable only by us. The
+/// temporary file lives alongside the target so the rename cannot cross
+/// filesystems.
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut tmpf = Builder::new()
+        .permissions(Permissions::from_mode(0o600))
+        .tempfile_in(dir)
+        .with_context(|| format!("Create temporary file in {:?}", dir))?;
+    tmpf.write_all(contents)?;
+    tmpf.as_file().sync_all()?;
+    tmpf.persist(path)
+        .with_context(|| format!("Replace token file: {:?}", path))?;
+
+    // Make sure the rename itself survives a crash.
+    File::open(dir)?.sync_all()?;
+    debug!(?path, "Synced token file");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const TOKEN: &[u8] = br#"{"access_token":"a","refresh_token":"r"}"#;
+
+    fn tamper(sealed: &[u8], edit: impl FnOnce(&mut serde_json::Value)) -> Vec<u8> {
+        let mut value: serde_json::Value = serde_json::from_slice(sealed).unwrap();
+        edit(&mut value);
+        serde_json::to_vec(&value).unwrap()
+    }
+
+    #[test]
+    fn passphrase_round_trip() {
+        let path = Path::new("token.json");
+        let sealed = seal(TOKEN, &TokenKey::passphrase(b"hunter2".to_vec())).unwrap();
+
+        let opened = unseal(
+            &sealed,
+            Some(&TokenKey::passphrase(b"hunter2".to_vec())),
+            path,
+        );
+        assert_eq!(opened.unwrap(), TOKEN);
+        assert!(unseal(
+            &sealed,
+            Some(&TokenKey::passphrase(b"hunter3".to_vec())),
+            path
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn key_file_round_trip() {
+        let path = Path::new("token.json");
+        let key = TokenKey::key_file([7u8; 32].to_vec());
+        let sealed = seal(TOKEN, &key).unwrap();
+        assert!(!sealed.windows(TOKEN.len()).any(|w| w == TOKEN));
+
+        assert_eq!(unseal(&sealed, Some(&key), path).unwrap(), TOKEN);
+        let other = TokenKey::key_file([8u8; 32].to_vec());
+        assert!(unseal(&sealed, Some(&other), path).is_err());
+        assert!(unseal(&sealed, None, path).is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let key = TokenKey::key_file([7u8; 32].to_vec());
+        let sealed = seal(TOKEN, &key).unwrap();
+        let tampered = tamper(&sealed, |value| {
+            let mut ciphertext = STANDARD
+                .decode(value["ciphertext"].as_str().unwrap())
+                .unwrap();
+            ciphertext[0] ^= 1;
+            value["ciphertext"] = STANDARD.encode(ciphertext).into();
+        });
+
+        assert!(unseal(&tampered, Some(&key), Path::new("token.json")).is_err());
+    }
+
+    #[test]
+    fn unexpected_iterations_are_rejected() {
+        let path = Path::new("token.json");
+        let key = TokenKey::key_file([7u8; 32].to_vec());
+        let sealed = seal(TOKEN, &key).unwrap();
+
+        let pbkdf2 = tamper(&sealed, |value| {
+            value["kdf"] = "pbkdf2".into();
+            value["iterations"] = u32::MAX.into();
+        });
+        assert!(unseal(&pbkdf2, Some(&key), path).is_err());
+        let hkdf = tamper(&sealed, |value| value["iterations"] = 1.into());
+        assert!(unseal(&hkdf, Some(&key), path).is_err());
+    }
+
+    #[test]
+    fn plaintext_passes_through() {
+        let path = Path::new("token.json");
+        let key = TokenKey::key_file([7u8; 32].to_vec());
+
+        assert_eq!(unseal(TOKEN, None, path).unwrap(), TOKEN);
+        assert_eq!(unseal(TOKEN, Some(&key), path).unwrap(), TOKEN);
+    }
+
+    #[test]
+    fn write_private_replaces_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token.json");
+        fs::write(&path, "old").unwrap();
+
+        write_private(&path, TOKEN).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), TOKEN);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
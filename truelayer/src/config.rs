@@ -1,15 +1,39 @@
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use std::{collections::HashMap, fs::File, net::SocketAddr, path::PathBuf, time::Duration};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use hyper::Uri;
 use serde::{Deserialize, Serialize};
 
-use crate::{ClientCreds, Environment};
+use crate::{ClientCreds, Environment, TokenKey};
+
+const DEFAULT_SCOPES: &[&str] = &[
+    "info",
+    "accounts",
+    "balance",
+    "cards",
+    "transactions",
+    "direct_debits",
+    "standing_orders",
+    "offline_access",
+];
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MainConfig {
     pub client_credentials: PathBuf,
     pub environment: Environment,
     pub request_timeout_s: Option<u64>,
+    // Encrypts token files at rest when set.
+    pub token_key: Option<TokenKeySource>,
+}
+
+/// Where to find the secret that token files are encrypted with.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum TokenKeySource {
+    // Names the environment variable holding the passphrase.
+    Passphrase { env: String },
+    // Eg: created with `head -c 32 /dev/urandom`.
+    KeyFile { path: PathBuf },
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ProviderConfig {
@@ -21,11 +45,62 @@ pub struct ProviderConfig {
     pub scrape_cards: bool,
     #[serde(default)]
     pub scrape_info: bool,
+    #[serde(default)]
+    pub scrape_standing_orders: bool,
+    #[serde(default)]
+    pub scrape_direct_debits: bool,
+    // How long after authenticating standing orders and direct debits can
+    // still be fetched.
+    pub recent_auth_window_s: Option<u64>,
+    // Some banks reject scopes they do not support.
+    pub scopes: Option<Vec<String>>,
+    // Which kinds of provider the bank picker offers, eg: `uk-ob-all`.
+    pub provider_filter: Option<Vec<String>>,
+    // Skips the bank picker altogether, eg: `ob-monzo`.
+    pub provider_id: Option<String>,
+}
+
+impl ProviderConfig {
+    pub fn recent_auth_window(&self) -> Duration {
+        Duration::from_secs(self.recent_auth_window_s.unwrap_or(300))
+    }
+
+    pub fn auth_scopes(&self) -> String {
+        match &self.scopes {
+            Some(scopes) => scopes.join(" "),
+            None => DEFAULT_SCOPES.join(" "),
+        }
+    }
+
+    pub fn auth_providers(&self, env: Environment) -> String {
+        match (&self.provider_filter, env) {
+            (Some(filter), _) => filter.join(" "),
+            (None, Environment::Sandbox) => "uk-cs-mock uk-ob-all uk-oauth-all".to_owned(),
+            (None, Environment::Live) => "uk-ob-all uk-oauth-all".to_owned(),
+        }
+    }
 }
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ScraperConfig {
     pub main: MainConfig,
     pub providers: HashMap<String, ProviderConfig>,
+    pub http: Option<HttpListenerConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HttpListenerConfig {
+    pub bind_address: SocketAddr,
+    // Where the browser reaches us, eg: via a reverse proxy. The redirect
+    // URI registered with TrueLayer is this plus `start-redirect`.
+    pub client_facing_url: String,
+}
+
+impl HttpListenerConfig {
+    pub fn client_facing_url(&self) -> Result<Uri> {
+        self.client_facing_url
+            .parse()
+            .with_context(|| format!("Parse client facing URL: {}", self.client_facing_url))
+    }
 }
 impl ScraperConfig {
     pub fn credentials(&self) -> Result<ClientCreds> {
@@ -44,6 +119,27 @@ impl ScraperConfig {
         Ok(client_creds)
     }
 
+    pub fn token_key(&self) -> Result<Option<TokenKey>> {
+        let (secret, new_key): (_, fn(Vec<u8>) -> TokenKey) = match &self.main.token_key {
+            None => return Ok(None),
+            Some(TokenKeySource::Passphrase { env }) => (
+                std::env::var(env)
+                    .with_context(|| format!("Reading token passphrase from environment: {}", env))?
+                    .into_bytes(),
+                TokenKey::passphrase,
+            ),
+            Some(TokenKeySource::KeyFile { path }) => (
+                std::fs::read(path)
+                    .with_context(|| format!("Reading token key file: {:?}", path))?,
+                TokenKey::key_file,
+            ),
+        };
+        if secret.is_empty() {
+            bail!("Token key is empty");
+        }
+        Ok(Some(new_key(secret)))
+    }
+
     pub fn provider(&self, name: &str) -> Result<&ProviderConfig> {
         if let Some(provider) = self.providers.get(name) {
             Ok(provider)
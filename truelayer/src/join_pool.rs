@@ -1,7 +1,7 @@
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
-use futures::{future::BoxFuture, Future, FutureExt};
+use futures::{future::BoxFuture, Future, FutureExt, TryFutureExt};
 use tokio::{sync::mpsc, task::JoinSet};
 use tracing::{instrument, trace};
 
@@ -21,10 +21,13 @@ pub struct JobPool {
 
 struct Job(BoxFuture<'static, Result<()>>);
 
+type ErrorHandler = Arc<dyn Fn(anyhow::Error) -> Result<()> + Send + Sync>;
+
 #[derive(Clone)]
 pub struct JobHandle {
     tx: mpsc::UnboundedSender<Job>,
     stats: Arc<Mutex<PoolStats>>,
+    on_error: Option<ErrorHandler>,
 }
 
 impl JobPool {
@@ -37,7 +40,11 @@ impl JobPool {
             stats: stats.clone(),
             has_terminated: false,
         };
-        let handle = JobHandle { tx, stats };
+        let handle = JobHandle {
+            tx,
+            stats,
+            on_error: None,
+        };
         (pool, handle)
     }
 
@@ -96,9 +103,30 @@ impl JobPool {
 }
 
 impl JobHandle {
+    /// Jobs spawned through the returned handle, and any they spawn in turn
+    /// through it, have their errors passed to `on_error` rather than
+    /// failing the pool, unless it hands them back.
+    pub fn with_error_handler(
+        &self,
+        on_error: impl Fn(anyhow::Error) -> Result<()> + Send + Sync + 'static,
+    ) -> JobHandle {
+        JobHandle {
+            on_error: Some(Arc::new(on_error)),
+            ..self.clone()
+        }
+    }
+
     pub fn spawn(&self, fut: impl Future<Output = Result<()>> + Send + 'static) -> Result<()> {
+        let job = match &self.on_error {
+            Some(on_error) => {
+                let on_error = on_error.clone();
+                fut.or_else(move |error| async move { on_error(error) })
+                    .boxed()
+            }
+            None => fut.boxed(),
+        };
         self.tx
-            .send(Job(fut.boxed()))
+            .send(Job(job))
             .map_err(|_| anyhow::anyhow!("Pool dropped?"))?;
         self.stats.lock().expect("lock").jobs_submitted += 1;
 
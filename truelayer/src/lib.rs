@@ -5,6 +5,8 @@ use secrecy::{ExposeSecret, Secret, Zeroize};
 use serde::{de::DeserializeOwned, Serialize, Serializer};
 use tracing::{debug, error};
 
+use crate::client::{OAuthError, TOKEN_PATH};
+
 mod auth;
 mod client;
 mod config;
@@ -12,10 +14,10 @@ mod join_pool;
 mod sync;
 
 pub use auth::authenticate;
-pub use client::{ClientCreds, Environment, TlClient};
-pub use config::{MainConfig, ProviderConfig, ScraperConfig};
+pub use client::{ClientCreds, Environment, ReauthRequired, TlClient, TokenKey};
+pub use config::{HttpListenerConfig, MainConfig, ProviderConfig, ScraperConfig, TokenKeySource};
 pub use join_pool::{JobHandle, JobPool};
-pub use sync::{sync_accounts, sync_cards, sync_info};
+pub use sync::{sync_account_extras, sync_accounts, sync_cards, sync_info, AccountExtras};
 
 fn serialize_secret<T: Zeroize + Serialize, S: Serializer>(
     secret: &Secret<T>,
@@ -40,9 +42,16 @@ async fn perform_request<R: DeserializeOwned, B: Fn() -> RequestBuilder>(
     async fn inner<R: DeserializeOwned, B: Fn() -> RequestBuilder>(build: B) -> Result<R> {
         let res = build().send().await?;
         if let Err(error) = res.error_for_status_ref() {
-            error!(%error, status=?res.status(), "Failed response");
+            let status = res.status();
+            let is_token_request = res.url().path() == TOKEN_PATH;
+            error!(%error, ?status, "Failed response");
             if let Ok(body) = res.text().await {
                 debug!(%error, ?body, "Response body");
+                if is_token_request && status.is_client_error() {
+                    if let Ok(oauth_error) = serde_json::from_str::<OAuthError>(&body) {
+                        return Err(oauth_error.into());
+                    }
+                }
             }
             Err(error.into())
         } else {
@@ -51,5 +60,11 @@ async fn perform_request<R: DeserializeOwned, B: Fn() -> RequestBuilder>(
         }
     }
 
-    retry_policy.retry(|| inner(&build)).await
+    // Retrying will not make the server change its mind about our grant.
+    retry_policy
+        .retry_if(
+            || inner(&build),
+            |error: &anyhow::Error| !error.is::<OAuthError>(),
+        )
+        .await
 }
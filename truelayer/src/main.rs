@@ -1,15 +1,21 @@
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use chrono::NaiveDate;
 use clap::{Parser, Subcommand};
 use futures::TryFutureExt;
 use reqwest::Client;
 use tokio::try_join;
-use tracing::{debug, instrument, Instrument, Span};
+use tracing::{debug, error, instrument, Instrument, Span};
 
 use tl_scraper::{
-    ClientCreds, Environment, JobHandle, JobPool, ProviderConfig, ScraperConfig, TlClient,
+    AccountExtras, ClientCreds, JobHandle, JobPool, ProviderConfig, ReauthRequired, ScraperConfig,
+    TlClient, TokenKey,
 };
 
 #[derive(Debug, Parser)]
@@ -68,6 +74,7 @@ async fn run() -> Result<()> {
     };
 
     let client_creds = config.credentials()?;
+    let token_key = config.token_key()?;
 
     let client = reqwest::Client::builder()
         .timeout(
@@ -88,17 +95,36 @@ async fn run() -> Result<()> {
                 config.main.environment,
                 provider,
                 &client_creds,
-                port.unwrap_or(5500),
+                token_key.as_ref(),
+                config.http.as_ref(),
+                port,
             )
             .await?;
         }
         Commands::Sync(ref sync_opts) => {
             let (pool, handle) = JobPool::new(sync_opts.concurrency.unwrap_or(1));
+            let needs_reauth = NeedsReauth::default();
 
             try_join!(
                 pool.run().map_err(|e| e.context("Job pool")),
-                sync_all(client, sync_opts, &config, &client_creds, handle),
+                sync_all(
+                    client,
+                    sync_opts,
+                    &config,
+                    &client_creds,
+                    token_key.as_ref(),
+                    &needs_reauth,
+                    handle
+                ),
             )?;
+
+            let needs_reauth = needs_reauth.lock().expect("lock");
+            if !needs_reauth.is_empty() {
+                bail!(
+                    "Re-authentication required for: {}",
+                    needs_reauth.iter().cloned().collect::<Vec<_>>().join(", ")
+                );
+            }
         }
     };
     Ok(())
@@ -109,46 +135,71 @@ async fn sync_all(
     sync_opts: &Sync,
     config: &ScraperConfig,
     client_creds: &ClientCreds,
+    token_key: Option<&TokenKey>,
+    needs_reauth: &NeedsReauth,
     handle: JobHandle,
 ) -> Result<()> {
     for provider_name in sync_opts.provider.iter() {
         let provider: &ProviderConfig = config.provider(provider_name)?;
-
-        sync(
+        let tl = Arc::new(TlClient::new(
             client.clone(),
             config.main.environment,
-            sync_opts,
-            provider_name,
-            provider,
+            &provider.user_token,
+            token_key.cloned(),
             client_creds,
-            handle.clone(),
-        )
-        .await
-        .with_context(|| format!("Sync scheduler: {}", &provider_name))?;
+        ));
+
+        // Whether it is noticed up front or by a job part way through,
+        // lapsed consent skips this provider; the others still get synced.
+        let provider_handle = {
+            let (needs_reauth, provider_name) = (needs_reauth.clone(), provider_name.clone());
+            handle
+                .with_error_handler(move |error| note_reauth(&needs_reauth, &provider_name, error))
+        };
+
+        if let Err(error) = sync(tl, sync_opts, provider_name, provider, provider_handle).await {
+            note_reauth(needs_reauth, provider_name, error)
+                .with_context(|| format!("Sync scheduler: {}", &provider_name))?;
+        }
     }
     drop(handle);
     Ok(())
 }
 
+// Providers whose consent has lapsed, so need `auth` running again.
+type NeedsReauth = Arc<Mutex<BTreeSet<String>>>;
+
+fn note_reauth(
+    needs_reauth: &NeedsReauth,
+    provider_name: &str,
+    error: anyhow::Error,
+) -> Result<()> {
+    if error.downcast_ref::<ReauthRequired>().is_none() {
+        return Err(error);
+    }
+    if needs_reauth
+        .lock()
+        .expect("lock")
+        .insert(provider_name.to_owned())
+    {
+        error!(provider=%provider_name, %error, "Run `auth` for this provider again");
+    }
+    Ok(())
+}
+
 #[instrument(skip_all, fields(provider=%provider_name))]
 async fn sync(
-    client: Client,
-    environment: Environment,
+    tl: Arc<TlClient>,
     Sync {
         from_date, to_date, ..
     }: &Sync,
     provider_name: &str,
     provider: &ProviderConfig,
-    client_creds: &ClientCreds,
     handle: JobHandle,
 ) -> Result<(), anyhow::Error> {
     let target_dir = Arc::from(provider.target_dir.clone().into_boxed_path());
-    let tl = Arc::new(TlClient::new(
-        client,
-        environment,
-        &provider.user_token,
-        client_creds,
-    ));
+
+    tl.ensure_access().await?;
 
     if provider.scrape_info {
         debug!("Scraping info");
@@ -158,11 +209,15 @@ async fn sync(
     }
     if provider.scrape_accounts {
         debug!("Scraping accounts");
+        let extras = AccountExtras::from_config(provider)
+            .if_recently_authed(&tl, provider)
+            .await?;
         handle.spawn(
             tl_scraper::sync_accounts(
                 tl.clone(),
                 target_dir.clone(),
                 *from_date..=*to_date,
+                extras,
                 handle.clone(),
             )
             .instrument(Span::current()),
@@ -1,7 +1,7 @@
 use std::{cmp::min, io::Write, ops::RangeInclusive, path::Path, sync::Arc};
 
 use anyhow::Result;
-use chrono::{Datelike, NaiveDate};
+use chrono::{Datelike, NaiveDate, Utc};
 use serde::Serialize;
 use tempfile::NamedTempFile;
 use tokio::task::spawn_blocking;
@@ -9,22 +9,95 @@ use tracing::{debug, info, instrument, Instrument, Span};
 
 use crate::{
     client::{AccountsResult, CardsResult},
-    JobHandle, TlClient,
+    JobHandle, ProviderConfig, TlClient,
 };
 
+/// Standing orders and direct debits are only served for a short while
+/// after the user authenticates, so are scraped only when asked for, and
+/// when we are still within that window.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AccountExtras {
+    pub standing_orders: bool,
+    pub direct_debits: bool,
+}
+
+impl AccountExtras {
+    pub fn from_config(provider: &ProviderConfig) -> Self {
+        Self {
+            standing_orders: provider.scrape_standing_orders,
+            direct_debits: provider.scrape_direct_debits,
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.standing_orders || self.direct_debits
+    }
+
+    /// Drops the extras unless the user authenticated recently enough.
+    pub async fn if_recently_authed(
+        self,
+        tl: &TlClient,
+        provider: &ProviderConfig,
+    ) -> Result<Self> {
+        if !self.any() {
+            return Ok(self);
+        }
+
+        let window = chrono::Duration::from_std(provider.recent_auth_window())?;
+        let authed_at = tl.authed_at().await?;
+        if authed_at.is_some_and(|authed_at| Utc::now() - authed_at <= window) {
+            Ok(self)
+        } else {
+            info!(
+                ?authed_at,
+                "Not authenticated recently; skipping standing orders and direct debits"
+            );
+            Ok(Self::default())
+        }
+    }
+}
+
 #[instrument(skip_all)]
 pub async fn sync_accounts(
     tl: Arc<TlClient>,
     target_dir: Arc<Path>,
     period: RangeInclusive<NaiveDate>,
+    extras: AccountExtras,
     jobs: JobHandle,
 ) -> Result<(), anyhow::Error> {
     info!(?period, "Scraping accounts for specified period");
     let accounts = accounts(tl.clone(), target_dir.clone()).await?;
     for account_item in accounts {
-        account(&jobs, &tl, &target_dir, account_item, period.clone())
-            .instrument(Span::current())
-            .await?;
+        account(
+            &jobs,
+            &tl,
+            &target_dir,
+            account_item,
+            period.clone(),
+            extras,
+        )
+        .instrument(Span::current())
+        .await?;
+    }
+    Ok(())
+}
+
+/// Scrapes just the standing orders and direct debits, eg: straight after
+/// authenticating.
+#[instrument(skip_all)]
+pub async fn sync_account_extras(
+    tl: Arc<TlClient>,
+    target_dir: Arc<Path>,
+    extras: AccountExtras,
+) -> Result<()> {
+    let accounts = accounts(tl.clone(), target_dir.clone()).await?;
+    for account in accounts {
+        if extras.standing_orders {
+            account_standing_orders(tl.clone(), target_dir.clone(), account.clone()).await?;
+        }
+        if extras.direct_debits {
+            account_direct_debits(tl.clone(), target_dir.clone(), account.clone()).await?;
+        }
     }
     Ok(())
 }
@@ -36,6 +109,7 @@ async fn account(
     target_dir: &Arc<Path>,
     account: AccountsResult,
     period: RangeInclusive<NaiveDate>,
+    extras: AccountExtras,
 ) -> Result<(), anyhow::Error> {
     jobs.spawn(
         account_balance(tl.clone(), target_dir.clone(), account.clone())
@@ -52,12 +126,13 @@ async fn account(
         )?;
     }
 
-    if false {
-        // Only available when you've _recently_ authenticated.
+    if extras.standing_orders {
         jobs.spawn(
             account_standing_orders(tl.clone(), target_dir.clone(), account.clone())
                 .instrument(Span::current()),
         )?;
+    }
+    if extras.direct_debits {
         jobs.spawn(
             account_direct_debits(tl.clone(), target_dir.clone(), account.clone())
                 .instrument(Span::current()),
@@ -183,12 +258,12 @@ async fn account_standing_orders(
     account: AccountsResult,
 ) -> Result<()> {
     info!("Fetch standing orders");
-    let bal = tl.account_standing_orders(&account.account_id).await?;
+    let orders = tl.account_standing_orders(&account.account_id).await?;
     let path = &target_dir
         .join("accounts")
         .join(account_dir_name(&account))
         .join("standing-orders.jsons");
-    write_jsons_atomically(path, bal.results).await?;
+    write_jsons_atomically(path, orders.results).await?;
     Ok(())
 }
 
@@ -199,12 +274,12 @@ async fn account_direct_debits(
     account: AccountsResult,
 ) -> Result<()> {
     info!("Fetch direct debits");
-    let bal = tl.account_direct_debits(&account.account_id).await?;
+    let debits = tl.account_direct_debits(&account.account_id).await?;
     let path = &target_dir
         .join("accounts")
         .join(account_dir_name(&account))
-        .join("standing-orders.jsons");
-    write_jsons_atomically(path, bal.results).await?;
+        .join("direct-debits.jsons");
+    write_jsons_atomically(path, debits.results).await?;
     Ok(())
 }
 